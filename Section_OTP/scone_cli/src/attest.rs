@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Deviations from a fully up-to-date, production-mode CAS enclave that attestation accepts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Tolerate {
+    OutdatedTcb,                // platform needs a TCB update (-G)
+    ConfigurationNeeded,        // platform needs additional configuration (-C)
+    SoftwareHardeningNeeded,    // platform needs software hardening (-S)
+    DebugMode,                  // CAS runs in debug mode - only for testing
+}
+
+impl Tolerate {
+    /// all values - as accepted by `from_name`
+    pub const ALL: [Tolerate; 4] = [Tolerate::OutdatedTcb, Tolerate::ConfigurationNeeded, Tolerate::SoftwareHardeningNeeded, Tolerate::DebugMode];
+
+    /// name as used in session descriptions, e.g., `outdated-tcb`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tolerate::OutdatedTcb => "outdated-tcb",
+            Tolerate::ConfigurationNeeded => "configuration-needed",
+            Tolerate::SoftwareHardeningNeeded => "software-hardening-needed",
+            Tolerate::DebugMode => "debug-mode",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Tolerate> {
+        Tolerate::ALL.into_iter().find(|t| t.name() == name)
+    }
+
+    /// option of `scone cas attest`
+    fn flag(&self) -> &'static str {
+        match self {
+            Tolerate::OutdatedTcb => "-G",
+            Tolerate::ConfigurationNeeded => "-C",
+            Tolerate::SoftwareHardeningNeeded => "-S",
+            Tolerate::DebugMode => "--only_for_testing-debug",
+        }
+    }
+}
+
+impl fmt::Display for Tolerate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What we expect from CAS: its MRENCLAVE and / or its signer. The public debug CAS of the exercises
+/// is not signed, i.e., it is attested by MRENCLAVE with `ignore_signer` and `Tolerate::DebugMode`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AttestationPolicy {
+    pub mrenclave: Option<String>,  // expected MRENCLAVE of CAS
+    pub mrsigner: Option<String>,   // expected MRSIGNER of CAS - default: signer of SCONE CAS
+    pub isvprodid: Option<u16>,     // expected product ID - requires mrsigner
+    pub isvsvn: Option<u16>,        // minimal security version - requires mrsigner
+    pub ignore_signer: bool,        // do not check the signer - only for testing
+    pub tolerate: Vec<Tolerate>,    // accepted deviations, e.g., outdated-tcb
+}
+
+impl AttestationPolicy {
+    /// attest CAS by its MRENCLAVE
+    pub fn mrenclave(mrenclave: &str) -> Self {
+        AttestationPolicy { mrenclave: Some(mrenclave.to_string()), ..AttestationPolicy::default() }
+    }
+
+    /// attest CAS by its signer, product ID and minimal security version
+    pub fn mrsigner(mrsigner: &str, isvprodid: u16, isvsvn: u16) -> Self {
+        AttestationPolicy { mrsigner: Some(mrsigner.to_string()), isvprodid: Some(isvprodid), isvsvn: Some(isvsvn), ..AttestationPolicy::default() }
+    }
+
+    /// accept deviation `tolerate`
+    pub fn with_tolerate(mut self, tolerate: Tolerate) -> Self {
+        if !self.tolerate.contains(&tolerate) {
+            self.tolerate.push(tolerate);
+        }
+        self
+    }
+
+    /// do not check the signer of CAS - only for testing, e.g., for the public debug CAS
+    pub fn with_ignore_signer(mut self) -> Self {
+        self.ignore_signer = true;
+        self
+    }
+
+    /// reason why this policy cannot be used - if any
+    pub fn problem(&self) -> Option<&'static str> {
+        if self.mrenclave.is_none() && self.mrsigner.is_none() && !self.ignore_signer {
+            Some("policy neither specifies a MRENCLAVE nor a MRSIGNER")
+        } else if self.mrenclave.is_none() && self.ignore_signer {
+            Some("policy ignores the signer but does not specify a MRENCLAVE")
+        } else if self.mrsigner.is_none() && (self.isvprodid.is_some() || self.isvsvn.is_some()) {
+            Some("product ID and security version require a MRSIGNER")
+        } else if self.mrenclave.as_deref().is_some_and(|m| !is_measurement(m)) {
+            Some("MRENCLAVE must consist of 64 hexadecimal digits")
+        } else if self.mrsigner.as_deref().is_some_and(|m| !is_measurement(m)) {
+            Some("MRSIGNER must consist of 64 hexadecimal digits")
+        } else {
+            None
+        }
+    }
+
+    /// options of `scone cas attest`
+    pub(crate) fn options(&self) -> String {
+        let mut options : Vec<String> = self.tolerate.iter().map(|t| t.flag().to_string()).collect();
+        if let Some(mrenclave) = &self.mrenclave {
+            options.push(format!("--mrenclave {}", mrenclave));
+        }
+        if let Some(mrsigner) = &self.mrsigner {
+            options.push(format!("--mrsigner {}", mrsigner));
+        }
+        if let Some(isvprodid) = self.isvprodid {
+            options.push(format!("--isvprodid {}", isvprodid));
+        }
+        if let Some(isvsvn) = self.isvsvn {
+            options.push(format!("--isvsvn {}", isvsvn));
+        }
+        if self.ignore_signer {
+            options.push("--only_for_testing-ignore-signer".to_string());
+        }
+        options.join(" ")
+    }
+}
+
+/// true if `value` is a MRENCLAVE or MRSIGNER, i.e., 64 hexadecimal digits - and hence safe to pass to the shell
+fn is_measurement(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Result of `attest_cas`: the certificate of the attested CAS and how it was attested.
+/// Store it in the state to be able to check later on that we still talk to this CAS (see `check_attestation`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CasAttestation {
+    pub cas_addr: String,           // address of the attested CAS, e.g., scone-cas.cf
+    pub policy: AttestationPolicy,  // policy used for the attestation
+    pub certificate: String,        // PEM encoded certificate of CAS - as returned by `scone cas show-certificate`
+    pub attested_at: String,        // time of the attestation (RFC 3339, UTC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measurements_are_hex() {
+        let hex = "a".repeat(64);
+        assert_eq!(AttestationPolicy::mrenclave(&hex).with_ignore_signer().problem(), None);
+        assert_eq!(AttestationPolicy::mrsigner(&hex.to_uppercase(), 1, 2).problem(), None);
+        for value in ["abc", &format!("{};id", &hex[..61]), &"g".repeat(64), &format!("{} ", hex)] {
+            assert!(AttestationPolicy::mrenclave(value).problem().is_some(), "{}", value);
+            assert!(AttestationPolicy::mrsigner(value, 1, 2).problem().is_some(), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use data_encoding::HEXLOWER;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+
+use crate::{Error, Result};
+
+/// default audit log - relative to the working directory, i.e., next to the state file of the tools
+pub const AUDIT_LOG: &str = "audit.log";
+
+/// environment variable that selects the audit log
+pub const AUDIT_LOG_ENV: &str = "SCONE_AUDIT_LOG";
+
+/// audit log of this process - set by `set_audit_log`
+static LOG: Mutex<Option<String>> = Mutex::new(None);
+
+/// Kind of an audited operation.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditKind {
+    SessionCreate,      // `scone session create` - creates or updates a session
+    CasAttest,          // `scone cas attest`
+    DockerRun,          // `docker run` of an OTP-protected service, e.g., signing an image
+    RollForward,        // the OTP secret was replaced
+    RollBack,           // the OTP secret of an earlier volume version was restored
+}
+
+/// Entry of the audit log: one JSON line per operation. Each entry contains the hash of the previous entry - hence,
+/// modifying, inserting or removing an entry breaks the chain (see `verify_audit_log`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub timestamp: String,      // RFC 3339
+    pub user: String,           // user that executed the operation
+    pub kind: AuditKind,
+    pub session: String,        // session the operation refers to - CAS address for `CasAttest`
+    pub hash: String,           // resulting hash of the session - empty if none
+    pub detail: String,         // e.g., the signed image or the volume version
+    pub success: bool,
+    pub prev: String,           // hash of the previous entry - empty for the first entry
+    pub entry_hash: String,     // SHA-256 of this entry without `entry_hash` - set by `audit`
+}
+
+impl AuditEntry {
+    /// successful operation `kind` on session `session` - executed now by the current user
+    pub fn new(kind: AuditKind, session: &str) -> Self {
+        let user = users::get_current_username().map(|u| u.to_string_lossy().to_string()).unwrap_or_else(|| "unknown".to_string());
+        AuditEntry {
+            timestamp: time::now_utc().rfc3339().to_string(),
+            user,
+            kind,
+            session: session.to_string(),
+            hash: String::new(),
+            detail: String::new(),
+            success: true,
+            prev: String::new(),
+            entry_hash: String::new(),
+        }
+    }
+
+    pub fn with_hash(mut self, hash: &str) -> Self {
+        self.hash = hash.trim().to_string();
+        self
+    }
+
+    pub fn with_detail(mut self, detail: &str) -> Self {
+        self.detail = detail.to_string();
+        self
+    }
+
+    pub fn with_success(mut self, success: bool) -> Self {
+        self.success = success;
+        self
+    }
+}
+
+/// append the operations of this process to file `path` instead of `SCONE_AUDIT_LOG` / `audit.log`
+pub fn set_audit_log(path: &str) {
+    *LOG.lock().unwrap_or_else(|e| e.into_inner()) = Some(path.to_string());
+}
+
+/// the audit log of this process: set by `set_audit_log`, environment variable `SCONE_AUDIT_LOG` or `audit.log`
+pub fn audit_log() -> String {
+    let log = LOG.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    log.or_else(|| std::env::var(AUDIT_LOG_ENV).ok().filter(|p| !p.is_empty())).unwrap_or_else(|| AUDIT_LOG.to_string())
+}
+
+/// SHA-256 of entry `entry` (a JSON object) without its field `entry_hash`
+fn entry_hash(entry: &Value) -> String {
+    let mut entry = entry.clone();
+    if let Some(m) = entry.as_object_mut() {
+        m.remove("entry_hash");
+    }
+    HEXLOWER.encode(&Sha256::digest(entry.to_string().as_bytes()))
+}
+
+/// append `entry` to the audit log (see `audit_log`) - chained to the last entry of the log.
+/// The log is locked while appending. Returns the entry as written.
+pub fn audit(mut entry: AuditEntry) -> Result<AuditEntry> {
+    let path = audit_log();
+    let io_error = |source| Error::Io { path: path.clone(), source };
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path).map_err(io_error)?;
+    file.lock().map_err(io_error)?;
+    let mut log = String::new();
+    file.read_to_string(&mut log).map_err(io_error)?;
+    entry.prev = match log.lines().rev().find(|l| !l.trim().is_empty()) {
+        Some(last) => serde_json::from_str::<Value>(last).ok()
+            .and_then(|v| v["entry_hash"].as_str().map(str::to_string))
+            .ok_or_else(|| Error::AuditLog { path: path.clone(), line: log.lines().count(), message: "last entry is corrupted".to_string() })?,
+        None => String::new(),
+    };
+    let mut value = serde_json::to_value(&entry).map_err(|source| Error::Json { context: format!("audit entry for '{}'", path), source })?;
+    entry.entry_hash = entry_hash(&value);
+    value["entry_hash"] = entry.entry_hash.clone().into();
+    writeln!(file, "{}", value).map_err(io_error)?;
+    file.sync_all().map_err(io_error)?;
+    // the lock is released when the file is closed
+    Ok(entry)
+}
+
+/// `audit` - failures are only logged: the operation already took place
+pub fn record(entry: AuditEntry) {
+    if let Err(e) = audit(entry) {
+        warn!("Cannot write audit log: {}", e);
+    }
+}
+
+/// read and verify the audit log `path`: each entry must be intact and refer to the hash of its predecessor.
+/// Returns the entries of the log. Fails with `Error::AuditLog` at the first entry that was modified, inserted or
+/// removed. Note that the chain cannot detect that entries were removed from the end of the log.
+pub fn verify_audit_log(path: &str) -> Result<Vec<AuditEntry>> {
+    let log = std::fs::read_to_string(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+    let mut entries : Vec<AuditEntry> = vec![];
+    for (i, line) in log.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let error = |message: String| Error::AuditLog { path: path.to_string(), line: i + 1, message };
+        let value : Value = serde_json::from_str(line).map_err(|e| error(format!("invalid entry: {}", e)))?;
+        let entry : AuditEntry = serde_json::from_value(value.clone()).map_err(|e| error(format!("invalid entry: {}", e)))?;
+        if entry.entry_hash != entry_hash(&value) {
+            return Err(error("entry was modified".to_string()))
+        }
+        let prev = entries.last().map(|e| e.entry_hash.as_str()).unwrap_or("");
+        if entry.prev != prev {
+            return Err(error("chain is broken - an entry before this one was modified, inserted or removed".to_string()))
+        }
+        entries.push(entry);
+    }
+    info!("Verified {} entries of audit log {}", entries.len(), path);
+    Ok(entries)
+}
@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use zeroize::Zeroizing;
+
+use crate::{crypt, schema, to_json_value, Error, Measurements, Result, Versioned};
+
+/// Backup of everything a tool needs to keep control of its sessions on another machine: the state (the only
+/// record of randomly chosen namespace names), the rendered sessions with their hashes, the MRENCLAVEs and the
+/// session templates. Written encrypted with `write_bundle` and restored with `read_bundle`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Bundle {
+    pub tool: String,                                   // tool that exported the bundle, e.g., otp_policy
+    pub created: String,                                // time of the export (RFC 3339)
+    pub state: Value,                                   // state object - including its schema version
+    pub sessions: BTreeMap<String, BundledSession>,     // rendered session descriptions by session name
+    pub mrenclaves: BTreeMap<String, String>,           // MRENCLAVE of each service
+    pub templates: BTreeMap<String, String>,            // session templates by file name, e.g., policy_admin.yml
+}
+
+/// Session description as rendered from the state at the time of the export - and the hash of its current version.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BundledSession {
+    pub hash: String,
+    pub yaml: String,
+}
+
+impl Bundle {
+    /// bundle of `state` exported by tool `tool` - the schema version of the state is recorded (see `Versioned`)
+    pub fn new<T : Serialize + Versioned>(tool: &str, state: &T) -> Result<Self> {
+        let mut state = to_json_value(state)?;
+        schema::set_schema_version(&mut state, T::SCHEMA_VERSION);
+        Ok(Bundle { tool: tool.to_string(), created: time::now_utc().rfc3339().to_string(), state, ..Bundle::default() })
+    }
+
+    /// add session `name` with the hash `hash` of its current version and its rendered description `yaml`
+    pub fn with_session(mut self, name: &str, hash: &str, yaml: &str) -> Self {
+        self.sessions.insert(name.to_string(), BundledSession { hash: hash.trim().to_string(), yaml: yaml.to_string() });
+        self
+    }
+
+    /// add the MRENCLAVEs of `measurements` - services whose MRENCLAVE is not yet known are skipped
+    pub fn with_mrenclaves(mut self, measurements: &Measurements) -> Self {
+        for (service, m) in measurements.iter().filter(|(_, m)| !m.mrenclave.is_empty()) {
+            self.mrenclaves.insert(service.clone(), m.mrenclave.clone());
+        }
+        self
+    }
+
+    /// add template `content` stored in file `file`
+    pub fn with_template(mut self, file: &str, content: &str) -> Self {
+        self.templates.insert(file.to_string(), content.to_string());
+        self
+    }
+
+    /// the state of the bundle - migrated to the current schema version of `T`
+    pub fn state<T : Versioned + for<'de> Deserialize<'de>>(&self) -> Result<T> {
+        schema::upgrade(self.state.clone(), &format!("bundle of {}", self.tool))
+    }
+}
+
+/// true if `text` is the content of a bundle written by `write_bundle`
+pub fn is_bundle(text: &str) -> bool {
+    serde_json::from_str::<Value>(text).map(|v| v["format"] == crypt::BUNDLE.format).unwrap_or(false)
+}
+
+/// encrypt `bundle` (for file `path`) with a key derived from `passphrase`
+pub fn encrypt_bundle(bundle: &Bundle, passphrase: &str, path: &str) -> Result<String> {
+    let plaintext = Zeroizing::new(serde_json::to_string(bundle).map_err(|source| Error::Json { context: format!("bundle for '{}'", path), source })?);
+    crypt::seal(&plaintext, passphrase, path, &crypt::BUNDLE)
+}
+
+/// decrypt the content `text` of bundle `path`
+pub fn decrypt_bundle(text: &str, passphrase: &str, path: &str) -> Result<Bundle> {
+    let plaintext = crypt::open(text, passphrase, path, &crypt::BUNDLE)?;
+    serde_json::from_str(&plaintext).map_err(|source| Error::Json { context: format!("bundle in '{}'", path), source })
+}
+
+/// write `bundle` encrypted to file `path` - the passphrase is taken from `SCONE_BUNDLE_PASSPHRASE` or asked for
+pub fn write_bundle(bundle: &Bundle, path: &str) -> Result<()> {
+    let encrypted = encrypt_bundle(bundle, &crypt::bundle_passphrase(path, true)?, path)?;
+    crate::write_atomic(path, &encrypted)
+}
+
+/// read and decrypt the bundle in file `path` - the passphrase is taken from `SCONE_BUNDLE_PASSPHRASE` or asked for
+pub fn read_bundle(path: &str) -> Result<Bundle> {
+    let text = fs::read_to_string(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+    if !is_bundle(&text) {
+        return Err(Error::Bundle { path: path.to_string(), message: "not a bundle".to_string() })
+    }
+    decrypt_bundle(&text, &crypt::bundle_passphrase(path, false)?, path)
+}
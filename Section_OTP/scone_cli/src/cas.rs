@@ -0,0 +1,206 @@
+use log::info;
+use reqwest::blocking::Client;
+use reqwest::{Certificate, Identity, Url};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::env;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use crate::{runner, CommandOutput, Error, Result};
+
+/// CAS used by the exercises
+pub const DEFAULT_CAS_ADDR: &str = "scone-cas.cf";
+
+/// default port of the CAS REST API
+pub const CAS_PORT: u16 = 8081;
+
+/// name in the certificate of CAS: requests are sent to this name and connected to the CAS address
+pub const CAS_SERVER_NAME: &str = "cas";
+
+/// Session as returned by `/v1/sessions/{name}`
+#[derive(Deserialize, Debug, Clone)]
+pub struct SessionResponse {
+    pub session: String,    // session description (YAML)
+}
+
+/// Public value as returned by `/v1/values/session={session},secret={secret}`
+#[derive(Deserialize, Debug, Clone)]
+pub struct ValueResponse {
+    pub value: String,
+}
+
+/// subset of `~/.cas/config.json` that we need
+#[derive(Deserialize)]
+struct CasConfig {
+    identity: String,       // PEM encoded certificate and private key of this client
+}
+
+/// Client for the REST API of CAS.
+///
+/// Requests are authenticated with the client certificate of the SCONE CLI and the CAS certificate
+/// is pinned, i.e., it is the only certificate that we trust.
+pub struct CasClient {
+    client: Client,
+    base_url: Url,
+}
+
+impl CasClient {
+    /// connect to CAS at `cas_addr` (`host` or `host:port`), trust only `cas_cert` and authenticate with `identity`.
+    /// Both certificates are PEM encoded; `identity` contains certificate and private key.
+    pub fn new(cas_addr: &str, cas_cert: &str, identity: Option<&str>) -> Result<Self> {
+        let addr = resolve(cas_addr)?;
+        let cas_cert = Certificate::from_pem(cas_cert.as_bytes())
+            .map_err(|e| Error::Certificate { message: format!("invalid CAS certificate: {}", e) })?;
+        let mut builder = Client::builder()
+            .use_rustls_tls()
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(cas_cert)
+            .resolve(CAS_SERVER_NAME, addr);
+        if let Some(identity) = identity {
+            let identity = Identity::from_pem(identity.as_bytes())
+                .map_err(|e| Error::Certificate { message: format!("invalid client identity: {}", e) })?;
+            builder = builder.identity(identity);
+        }
+        let client = builder.build()
+            .map_err(|e| Error::Certificate { message: e.to_string() })?;
+        let base_url = Url::parse(&format!("https://{}:{}", CAS_SERVER_NAME, addr.port()))
+            .map_err(|e| Error::Http { url: cas_addr.to_string(), message: e.to_string() })?;
+        Ok(CasClient { client, base_url })
+    }
+
+    /// connect to CAS at `cas_addr` using the identity of the SCONE CLI (`~/.cas/config.json`) and the
+    /// CAS certificate as returned by `scone cas show-certificate`, i.e., the certificate of the attested CAS.
+    pub fn from_cas_config(cas_addr: &str) -> Result<Self> {
+        let identity = cas_identity()?;
+        let output : CommandOutput = runner().run("scone cas show-certificate").into();
+        if output.code != 0 || output.stdout.trim().is_empty() {
+            return Err(if output.docker_unavailable() { Error::Docker { output } } else { Error::Certificate { message: format!("cannot retrieve CAS certificate: {}", output.stderr.trim()) } });
+        }
+        CasClient::new(cas_addr, &output.stdout, Some(&identity))
+    }
+
+    /// session `name` - the current version or, if `hash` is given, the version with this hash
+    pub fn session(&self, name: &str, hash: Option<&str>) -> Result<SessionResponse> {
+        let mut url = self.url(&["v1", "sessions", name]);
+        if let Some(hash) = hash {
+            url.query_pairs_mut().append_pair("hash", hash);
+        }
+        self.get(url)
+    }
+
+    /// public value `secret` exported by session `session`
+    pub fn value(&self, session: &str, secret: &str) -> Result<ValueResponse> {
+        self.get(self.url(&["v1", "values", &format!("session={},secret={}", session, secret)]))
+    }
+
+    /// URL of the given path - each segment is percent-encoded, i.e., `/` in a session name does not start a new segment
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut().expect("https URL has a path").clear().extend(segments);
+        url
+    }
+
+    fn get<T: DeserializeOwned>(&self, url: Url) -> Result<T> {
+        info!("GET {}", url);
+        let response = self.client.get(url.clone()).send()
+            .map_err(|e| Error::Http { url: url.to_string(), message: e.to_string() })?;
+        let status = response.status();
+        let body = response.text()
+            .map_err(|e| Error::Http { url: url.to_string(), message: e.to_string() })?;
+        if !status.is_success() {
+            return Err(Error::Cas { url: url.to_string(), status: status.as_u16(), body });
+        }
+        serde_json::from_str(&body).map_err(|source| Error::Json { context: format!("response of {}", url), source })
+    }
+}
+
+/// PEM encoded client certificate and key of the SCONE CLI as stored in `~/.cas/config.json`
+pub fn cas_identity() -> Result<String> {
+    let filename = format!("{}/.cas/config.json", env::var("HOME").unwrap_or_default());
+    let config = fs::read_to_string(&filename).map_err(|source| Error::Io { path: filename.clone(), source })?;
+    let config : CasConfig = serde_json::from_str(&config).map_err(|source| Error::Json { context: filename, source })?;
+    Ok(config.identity)
+}
+
+fn resolve(cas_addr: &str) -> Result<SocketAddr> {
+    let addr = if cas_addr.contains(':') { cas_addr.to_string() } else { format!("{}:{}", cas_addr, CAS_PORT) };
+    addr.to_socket_addrs()
+        .map_err(|e| Error::Http { url: addr.clone(), message: e.to_string() })?
+        .next()
+        .ok_or_else(|| Error::Http { url: addr.clone(), message: "cannot resolve address".to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// TLS stand-in for CAS with a self-signed certificate for `CAS_SERVER_NAME`: answers each request with
+    /// `body` and returns the certificate, the address and the request lines it received
+    fn cas_stand_in(requests: usize, body: &'static str) -> (String, String, thread::JoinHandle<Vec<String>>) {
+        let cert = rcgen::generate_simple_self_signed(vec![CAS_SERVER_NAME.to_string()]).unwrap();
+        let pem = cert.cert.pem();
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![CertificateDer::from(cert.cert.der().to_vec())],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der())))
+            .unwrap();
+        let config = Arc::new(config);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut lines = vec![];
+            for _ in 0..requests {
+                let (tcp, _) = listener.accept().unwrap();
+                let connection = rustls::ServerConnection::new(config.clone()).unwrap();
+                let mut stream = BufReader::new(rustls::StreamOwned::new(connection, tcp));
+                let mut line = String::new();
+                if stream.read_line(&mut line).is_err() {
+                    continue    // e.g., the client rejected our certificate
+                }
+                lines.push(line.trim().to_string());
+                loop {
+                    let mut header = String::new();
+                    if stream.read_line(&mut header).unwrap() == 0 || header.trim().is_empty() {
+                        break
+                    }
+                }
+                let stream = stream.get_mut();
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).unwrap();
+                stream.flush().unwrap();
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+            }
+            lines
+        });
+        (pem, addr, server)
+    }
+
+    #[test]
+    fn session_names_are_percent_encoded() {
+        let (pem, addr, server) = cas_stand_in(3, r#"{"session":"name: x","value":"v"}"#);
+        let client = CasClient::new(&addr, &pem, None).unwrap();
+        assert_eq!(client.session("team/project/env", None).unwrap().session, "name: x");
+        client.session("a?b&c#d", Some("h&1")).unwrap();
+        assert_eq!(client.value("team/env", "otp_secret").unwrap().value, "v");
+        assert_eq!(server.join().unwrap(), vec![
+            "GET /v1/sessions/team%2Fproject%2Fenv HTTP/1.1",
+            "GET /v1/sessions/a%3Fb&c%23d?hash=h%261 HTTP/1.1",
+            "GET /v1/values/session=team%2Fenv,secret=otp_secret HTTP/1.1",
+        ]);
+    }
+
+    #[test]
+    fn other_certificates_are_rejected() {
+        let (_, addr, server) = cas_stand_in(1, "{}");
+        let other = rcgen::generate_simple_self_signed(vec![CAS_SERVER_NAME.to_string()]).unwrap().cert.pem();
+        let client = CasClient::new(&addr, &other, None).unwrap();
+        assert!(matches!(client.session("session", None), Err(Error::Http { .. })));
+        assert!(server.join().unwrap().is_empty());
+    }
+}
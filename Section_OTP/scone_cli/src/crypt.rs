@@ -0,0 +1,236 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use data_encoding::BASE64;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+use crate::{lock_state, Error, Result};
+
+/// environment variable that contains the passphrase of encrypted state files
+pub const PASSPHRASE_ENV: &str = "SCONE_STATE_PASSPHRASE";
+
+/// environment variable that contains the passphrase of bundles (see `write_bundle`)
+pub const BUNDLE_PASSPHRASE_ENV: &str = "SCONE_BUNDLE_PASSPHRASE";
+
+/// marks a state file as encrypted
+const FORMAT: &str = "scone-cli-encrypted-state";
+
+/// upper bounds of the Argon2 parameters of files we decrypt - a corrupted or tampered file must not make us
+/// allocate gigabytes of memory before the authentication of the ciphertext fails
+const MAX_M_COST: u32 = 4 * Params::DEFAULT_M_COST;
+const MAX_T_COST: u32 = 4 * Params::DEFAULT_T_COST;
+const MAX_P_COST: u32 = 4 * Params::DEFAULT_P_COST;
+
+/// passphrase used for the state files of this process - set by `set_state_passphrase` or the first prompt
+static PASSPHRASE: Mutex<Option<Zeroizing<String>>> = Mutex::new(None);
+
+/// Encrypted state file: the state (JSON) is encrypted with XChaCha20-Poly1305 using a key
+/// derived from a passphrase with Argon2id.
+#[derive(Serialize, Deserialize, Debug)]
+struct EncryptedState {
+    format: String,
+    version: u32,
+    kdf: Kdf,
+    cipher: String,
+    nonce: String,          // base64
+    ciphertext: String,     // base64
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Kdf {
+    algorithm: String,
+    m_cost: u32,            // memory in KiB
+    t_cost: u32,            // iterations
+    p_cost: u32,            // parallelism
+    salt: String,           // base64
+}
+
+/// use `passphrase` for all state files read and written by this process (instead of asking the user)
+pub fn set_state_passphrase(passphrase: &str) {
+    *PASSPHRASE.lock().unwrap_or_else(|e| e.into_inner()) = Some(Zeroizing::new(passphrase.to_string()));
+}
+
+/// passphrase set for this process or via environment variable `SCONE_STATE_PASSPHRASE` - if any
+pub(crate) fn known_passphrase() -> Option<Zeroizing<String>> {
+    let cached = PASSPHRASE.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    cached.or_else(|| std::env::var(PASSPHRASE_ENV).ok().filter(|p| !p.is_empty()).map(Zeroizing::new))
+}
+
+/// the known passphrase - or ask the user for the passphrase of state file `path`.
+/// A new passphrase (`new` is set) must be typed twice.
+pub(crate) fn state_passphrase(path: &str, new: bool) -> Result<Zeroizing<String>> {
+    if let Some(passphrase) = known_passphrase() {
+        return Ok(passphrase)
+    }
+    let passphrase = prompt_passphrase(&format!("Passphrase for state file '{}': ", path), path, new, &STATE)?;
+    set_state_passphrase(&passphrase);
+    Ok(passphrase)
+}
+
+/// passphrase of bundle `path` - taken from environment variable `SCONE_BUNDLE_PASSPHRASE` or asked for.
+/// A new passphrase (`new` is set) must be typed twice.
+pub(crate) fn bundle_passphrase(path: &str, new: bool) -> Result<Zeroizing<String>> {
+    match std::env::var(BUNDLE_PASSPHRASE_ENV).ok().filter(|p| !p.is_empty()) {
+        Some(passphrase) => Ok(Zeroizing::new(passphrase)),
+        None => prompt_passphrase(&format!("Passphrase for bundle '{}': ", path), path, new, &BUNDLE),
+    }
+}
+
+fn prompt_passphrase(prompt: &str, path: &str, new: bool, sealed: &Sealed) -> Result<Zeroizing<String>> {
+    let prompt_error = |source| Error::Io { path: "terminal".to_string(), source };
+    let passphrase = Zeroizing::new(rpassword::prompt_password(prompt).map_err(prompt_error)?);
+    if new {
+        let repeated = Zeroizing::new(rpassword::prompt_password("Repeat passphrase: ").map_err(prompt_error)?);
+        if passphrase != repeated {
+            return Err((sealed.error)(path, "passphrases do not match".to_string()))
+        }
+    }
+    if passphrase.is_empty() {
+        return Err((sealed.error)(path, "empty passphrase".to_string()))
+    }
+    Ok(passphrase)
+}
+
+/// true if `text` is the content of an encrypted state file
+pub fn is_encrypted_state(text: &str) -> bool {
+    serde_json::from_str::<EncryptedState>(text).map(|e| e.format == FORMAT).unwrap_or(false)
+}
+
+/// true if `write_state` encrypts state file `path`: a passphrase is known or the file is already encrypted
+pub fn encrypts_state(path: &str) -> bool {
+    known_passphrase().is_some() || fs::read_to_string(path).map(|text| is_encrypted_state(&text)).unwrap_or(false)
+}
+
+/// Kind of an encrypted file: state files and bundles (see `write_bundle`) use the same encryption but different
+/// formats - so that one cannot be mistaken for the other - and different errors.
+pub(crate) struct Sealed {
+    pub(crate) format: &'static str,
+    what: &'static str,                     // used in error messages, e.g., "state"
+    error: fn(&str, String) -> Error,       // error for file `path` with a message
+}
+
+/// encrypted state files
+const STATE: Sealed = Sealed {
+    format: FORMAT,
+    what: "state",
+    error: |path, message| Error::StateEncryption { path: path.to_string(), message },
+};
+
+/// encrypted bundles written by `write_bundle`
+pub(crate) const BUNDLE: Sealed = Sealed {
+    format: "scone-cli-bundle",
+    what: "bundle",
+    error: |path, message| Error::Bundle { path: path.to_string(), message },
+};
+
+/// the key is wiped when dropped
+fn derive_key(kdf: &Kdf, passphrase: &str, path: &str, sealed: &Sealed) -> Result<Zeroizing<[u8; 32]>> {
+    let error = |message: String| (sealed.error)(path, message);
+    if kdf.algorithm != "argon2id" {
+        return Err(error(format!("unsupported key derivation function '{}'", kdf.algorithm)))
+    }
+    if kdf.m_cost > MAX_M_COST || kdf.t_cost > MAX_T_COST || kdf.p_cost > MAX_P_COST {
+        return Err(error(format!("key derivation parameters exceed the limits (m_cost {}, t_cost {}, p_cost {})", MAX_M_COST, MAX_T_COST, MAX_P_COST)))
+    }
+    let salt = BASE64.decode(kdf.salt.as_bytes()).map_err(|e| error(format!("invalid salt: {}", e)))?;
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32)).map_err(|e| error(e.to_string()))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &salt, key.as_mut())
+        .map_err(|e| error(e.to_string()))?;
+    Ok(key)
+}
+
+/// encrypt the state `plaintext` (of file `path`) with a key derived from `passphrase`
+pub fn encrypt_state(plaintext: &str, passphrase: &str, path: &str) -> Result<String> {
+    seal(plaintext, passphrase, path, &STATE)
+}
+
+/// decrypt the content `text` of encrypted state file `path` - the plaintext is wiped when dropped
+pub fn decrypt_state(text: &str, passphrase: &str, path: &str) -> Result<Zeroizing<String>> {
+    open(text, passphrase, path, &STATE)
+}
+
+/// encrypt `plaintext` (of file `path`) with a key derived from `passphrase` - in format `sealed`
+pub(crate) fn seal(plaintext: &str, passphrase: &str, path: &str, sealed: &Sealed) -> Result<String> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let kdf = Kdf {
+        algorithm: "argon2id".to_string(),
+        m_cost: Params::DEFAULT_M_COST,
+        t_cost: Params::DEFAULT_T_COST,
+        p_cost: Params::DEFAULT_P_COST,
+        salt: BASE64.encode(&salt),
+    };
+    let key = derive_key(&kdf, passphrase, path, sealed)?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(Key::from_slice(key.as_ref()))
+        .encrypt(&nonce, plaintext.as_bytes())
+        .map_err(|_| (sealed.error)(path, "encryption failed".to_string()))?;
+    let encrypted = EncryptedState {
+        format: sealed.format.to_string(),
+        version: 1,
+        kdf,
+        cipher: "xchacha20poly1305".to_string(),
+        nonce: BASE64.encode(&nonce),
+        ciphertext: BASE64.encode(&ciphertext),
+    };
+    serde_json::to_string_pretty(&encrypted).map_err(|source| Error::Json { context: format!("encrypted {} for '{}'", sealed.what, path), source })
+}
+
+/// decrypt the content `text` of file `path` - encrypted in format `sealed`. The plaintext is wiped when dropped.
+pub(crate) fn open(text: &str, passphrase: &str, path: &str, sealed: &Sealed) -> Result<Zeroizing<String>> {
+    let error = |message: &str| (sealed.error)(path, message.to_string());
+    let encrypted : EncryptedState = serde_json::from_str(text).map_err(|source| Error::Json { context: format!("encrypted {} in '{}'", sealed.what, path), source })?;
+    if encrypted.format != sealed.format || encrypted.version != 1 || encrypted.cipher != "xchacha20poly1305" {
+        return Err(error(&format!("unsupported format of encrypted {}", sealed.what)))
+    }
+    let key = derive_key(&encrypted.kdf, passphrase, path, sealed)?;
+    let nonce = BASE64.decode(encrypted.nonce.as_bytes()).map_err(|_| error("invalid nonce"))?;
+    if nonce.len() != 24 {
+        return Err(error("invalid nonce"))
+    }
+    let ciphertext = BASE64.decode(encrypted.ciphertext.as_bytes()).map_err(|_| error("invalid ciphertext"))?;
+    let plaintext = XChaCha20Poly1305::new(Key::from_slice(key.as_ref()))
+        .decrypt(XNonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| error("wrong passphrase or corrupted file"))?;
+    match String::from_utf8(plaintext) {
+        Ok(plaintext) => Ok(Zeroizing::new(plaintext)),
+        Err(e) => {
+            drop(Zeroizing::new(e.into_bytes()));
+            Err(error(&format!("decrypted {} is not valid UTF-8", sealed.what)))
+        },
+    }
+}
+
+/// migrate state file `path` to the encrypted format. Returns false if the file is already encrypted.
+pub fn encrypt_state_file(path: &str) -> Result<bool> {
+    lock_state(path)?;
+    let text = Zeroizing::new(fs::read_to_string(path).map_err(|source| Error::Io { path: path.to_string(), source })?);
+    if is_encrypted_state(&text) {
+        return Ok(false)
+    }
+    // make sure that we do not encrypt garbage
+    let _ : serde_json::Value = serde_json::from_str(&text).map_err(|source| Error::Json { context: format!("state in '{}'", path), source })?;
+    let encrypted = encrypt_state(&text, &state_passphrase(path, true)?, path)?;
+    crate::write_atomic(path, &encrypted)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excessive_kdf_parameters_are_rejected() {
+        let kdf = |m_cost, t_cost, p_cost| Kdf { algorithm: "argon2id".to_string(), m_cost, t_cost, p_cost, salt: BASE64.encode(&[0u8; 16]) };
+        for kdf in [kdf(MAX_M_COST + 1, 1, 1), kdf(Params::MIN_M_COST, MAX_T_COST + 1, 1), kdf(Params::MIN_M_COST, 1, MAX_P_COST + 1)] {
+            assert!(matches!(derive_key(&kdf, "passphrase", "state.js", &STATE), Err(Error::StateEncryption { .. })));
+        }
+        assert!(derive_key(&kdf(Params::MIN_M_COST, 1, 1), "passphrase", "state.js", &STATE).is_ok());
+    }
+}
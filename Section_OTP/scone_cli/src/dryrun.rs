@@ -0,0 +1,65 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::{orchestrate, render_session, session_updates, Error, Result, SessionSpec};
+
+/// name of the manifest written by `write_manifest`
+pub const MANIFEST: &str = "manifest.json";
+
+/// What `create_session` would do with a session - as far as we can tell from the local state only.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionAction {
+    Create,     // hash unknown: session is created (or updated if it already exists in CAS)
+    Update,     // force is set: session is updated
+    Unchanged,  // hash known and force not set: session is not touched
+}
+
+/// Session rendered by `dry_run_session` - one entry of the manifest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenderedSession {
+    pub session: String,
+    pub file: String,                   // file containing the rendered session - relative to the output directory
+    pub action: SessionAction,
+    pub predecessor: Option<String>,    // hash used as predecessor - taken from the state
+}
+
+/// render the template of session `name` like `create_session` would and write it to directory `dir` -
+/// without contacting CAS or docker. Since CAS is not asked, the hash in the state is used as predecessor.
+pub fn dry_run_session<T : Serialize>(name: &str, hash: &str, template: &str, state: &T, force: bool, dir: &str) -> Result<RenderedSession> {
+    let predecessor = if hash.is_empty() { None } else { Some(hash.trim().to_string()) };
+    let action = match (&predecessor, force) {
+        (None, _) => SessionAction::Create,
+        (Some(_), true) => SessionAction::Update,
+        (Some(_), false) => SessionAction::Unchanged,
+    };
+    let yaml = render_session(name, template, state, predecessor.as_deref())?;
+    let file = format!("{}.yaml", name);
+    let path = Path::new(dir).join(&file);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|source| Error::Io { path: parent.display().to_string(), source })?;
+    }
+    fs::write(&path, yaml).map_err(|source| Error::Io { path: path.display().to_string(), source })?;
+    info!("Rendered session {} to {}", name, path.display());
+    Ok(RenderedSession { session: name.to_string(), file, action, predecessor })
+}
+
+/// render the sessions of `specs` like `apply_sessions` would - in the order in which they would be applied - to
+/// directory `dir` and write the manifest. Without contacting CAS or docker (see `dry_run_session`).
+pub fn dry_run_sessions<T : Serialize>(specs: &[SessionSpec], state: &T, force: bool, dir: &str) -> Result<Vec<RenderedSession>> {
+    let sessions = session_updates(specs, state, force)?.into_iter().map(|update| {
+        let spec = orchestrate::spec(specs, &update.name);
+        dry_run_session(&spec.name, &spec.hash, &spec.template, state, update.update, dir)
+    }).collect::<Result<Vec<_>>>()?;
+    write_manifest(dir, &sessions)?;
+    Ok(sessions)
+}
+
+/// write the list of rendered sessions to `dir/manifest.json`
+pub fn write_manifest(dir: &str, sessions: &[RenderedSession]) -> Result<()> {
+    let path = Path::new(dir).join(MANIFEST);
+    let manifest = serde_json::to_string_pretty(sessions).map_err(|source| Error::Json { context: "manifest".to_string(), source })?;
+    fs::write(&path, manifest).map_err(|source| Error::Io { path: path.display().to_string(), source })
+}
@@ -0,0 +1,134 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::{to_json_value, Error, Result};
+
+/// variables of the `state.env` files of the bash exercises and the corresponding fields of the Rust state:
+/// the namespace and the session (`CLI_SESSION`) with their hashes.
+pub const STATE_ENV_FIELDS: &[(&str, &str)] = &[
+    ("NS", "namespace"),
+    ("NAMESPACE", "namespace"),
+    ("NAMESPACE_SESSION_HASH", "namespace_hash"),
+    ("CLI_SESSION", "session"),
+    ("CLI_SESSION_HASH", "session_hash"),
+];
+
+/// read the variables of a `state.env` file as written by the bash exercises, i.e., lines of the form
+/// `export VAR="value"`. Lines that are not variable assignments are ignored.
+pub fn read_state_env(path: &str) -> Result<BTreeMap<String, String>> {
+    let text = fs::read_to_string(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+    Ok(parse_state_env(&text))
+}
+
+/// parse the variables of the content of a `state.env` file - see `read_state_env`
+pub fn parse_state_env(text: &str) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let assignment = line.strip_prefix("export ").map(str::trim_start).unwrap_or(line);
+        match assignment.split_once('=') {
+            Some((name, value)) if is_name(name) => {
+                vars.insert(name.to_string(), unquote(value));
+            },
+            _ => warn!("Ignoring line {}: not a variable assignment: {}", n + 1, line),
+        }
+    }
+    vars
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && !name.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// value of an assignment: `"..."` (with backslash escapes), `'...'` or a plain word
+fn unquote(value: &str) -> String {
+    let mut chars = value.chars();
+    match chars.next() {
+        Some('"') => {
+            let mut result = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => match chars.next() {
+                        Some(c @ ('"' | '\\' | '$' | '`')) => result.push(c),
+                        Some(c) => { result.push('\\'); result.push(c) },
+                        None => result.push('\\'),
+                    },
+                    c => result.push(c),
+                }
+            }
+            result
+        },
+        Some('\'') => chars.take_while(|c| *c != '\'').collect(),
+        _ => value.split_whitespace().next().unwrap_or_default().to_string(),
+    }
+}
+
+/// adopt the variables `vars` of a `state.env` file into `state`: for each pair (variable, field) of `fields`,
+/// the field of the state is set to the value of the variable - if the variable is set and not empty. Fails with
+/// `Error::StateEnv` if the state has no string field with that name. Variables not listed in `fields` are not
+/// imported. Returns the names of the fields that were set.
+pub fn import_state_env<T : Serialize + for<'de> Deserialize<'de>>(state: &mut T, vars: &BTreeMap<String, String>, fields: &[(&str, &str)]) -> Result<Vec<String>> {
+    let mut j : Value = to_json_value(&state)?;
+    let mut imported = vec![];
+    for (var, field) in fields {
+        if !matches!(j.get(*field), Some(Value::String(_))) {
+            return Err(Error::StateEnv { variable: var.to_string(), message: format!("state has no field {}", field) })
+        }
+        if let Some(value) = vars.get(*var).filter(|v| !v.is_empty()) {
+            info!("Importing {}={} into field {}", var, value, field);
+            j[*field] = value.as_str().into();
+            if !imported.iter().any(|f| f == field) {
+                imported.push(field.to_string());
+            }
+        }
+    }
+    let ignored : Vec<&str> = vars.keys().map(String::as_str).filter(|v| !fields.iter().any(|(var, _)| var == v)).collect();
+    if !ignored.is_empty() {
+        warn!("Not importing {}: only {} are imported", ignored.join(", "), fields.iter().map(|(var, _)| *var).collect::<Vec<_>>().join(", "));
+    }
+    *state = serde_json::from_value(j).map_err(|source| Error::Json { context: "state".to_string(), source })?;
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Default)]
+    struct State {
+        namespace: String,
+        namespace_hash: String,
+        session: String,
+        session_hash: String,
+    }
+
+    #[test]
+    fn namespace_and_session_are_imported() {
+        // state.env of Exercise6
+        let vars = parse_state_env("export NS=\"ns\"\nexport NAMESPACE_SESSION_HASH=\"h\"\nexport CLI_SESSION=\"ns/cli\"\nexport CLI_SESSION_HASH=\"c\"\nexport IMAGE=\"cli\"\n");
+        let mut state = State { session: "ns/otpqr-x".to_string(), ..State::default() };
+        assert_eq!(import_state_env(&mut state, &vars, STATE_ENV_FIELDS).unwrap(), vec!["namespace", "namespace_hash", "session", "session_hash"]);
+        assert_eq!((state.namespace.as_str(), state.namespace_hash.as_str()), ("ns", "h"));
+        assert_eq!((state.session.as_str(), state.session_hash.as_str()), ("ns/cli", "c"));
+
+        // state.env of an exercise without a session
+        let mut state = State { session: "ns/otpqr-x".to_string(), ..State::default() };
+        assert_eq!(import_state_env(&mut state, &parse_state_env("export NS=ns\n"), STATE_ENV_FIELDS).unwrap(), vec!["namespace"]);
+        assert_eq!((state.session.as_str(), state.session_hash.as_str()), ("ns/otpqr-x", ""));
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let vars = parse_state_env("export NS=ns\n");
+        let mut state = State::default();
+        assert!(matches!(import_state_env(&mut state, &vars, &[("NS", "namespace"), ("X", "unknown")]), Err(Error::StateEnv { .. })));
+        assert!(state.namespace.is_empty());
+    }
+}
@@ -0,0 +1,324 @@
+use std::fmt;
+use std::io;
+
+use crate::{LintIssue, TemplateVariable};
+
+/// Exit code, stdout and stderr of a command executed via `scone!` or `sh!`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl From<(i32, String, String)> for CommandOutput {
+    fn from((code, stdout, stderr): (i32, String, String)) -> Self {
+        CommandOutput { code, stdout, stderr }
+    }
+}
+
+impl CommandOutput {
+    /// true if the command could not reach docker (or podman) at all, i.e., docker is not installed,
+    /// the shell could not be spawned or the docker daemon is not reachable.
+    pub fn docker_unavailable(&self) -> bool {
+        self.code == 126
+            || self.code == 127
+            || self.stderr.contains("Cannot connect to the Docker daemon")
+            || self.stderr.contains("docker: not found")
+            || self.stderr.contains("docker: command not found")
+            || self.stderr.contains("Cannot connect to Podman")
+            || self.stderr.contains("podman: not found")
+    }
+
+    /// classify the failure of a `scone` command from its stderr. stdout is not considered: it might echo session
+    /// descriptions, hashes and MRENCLAVEs. URLs are ignored as well - they contain session names. HTTP status codes
+    /// are only recognised in the form the CLI reports them, e.g., `404 Not Found` or `HTTP 404`, and phrases only as
+    /// whole words.
+    pub fn failure_kind(&self) -> FailureKind {
+        if self.code == 126 || self.code == 127 {
+            // docker / the shell cannot be executed at all - retrying does not help
+            return FailureKind::Other
+        }
+        let stderr = without_urls(&self.stderr.to_lowercase());
+        let contains = |phrases: &[&str]| phrases.iter().any(|p| contains_phrase(&stderr, p));
+        match http_status(&stderr) {
+            Some(502..=504) => return FailureKind::Unavailable,
+            Some(409) => return FailureKind::PredecessorMismatch,
+            Some(401 | 403) => return FailureKind::PermissionDenied,
+            Some(404) => return FailureKind::NotFound,
+            _ => {},
+        }
+        if contains(&["cannot connect to the docker daemon", "cannot connect to podman", "connection refused", "connection reset",
+                      "timed out", "temporarily unavailable", "service unavailable", "bad gateway", "gateway timeout",
+                      "could not connect", "error sending request", "dns error", "broken pipe"]) {
+            FailureKind::Unavailable
+        } else if contains(&["predecessor mismatch", "predecessor does not match", "wrong predecessor", "not the current version"]) {
+            FailureKind::PredecessorMismatch
+        } else if contains(&["permission denied", "access denied", "not authorized", "unauthorized", "forbidden"]) {
+            FailureKind::PermissionDenied
+        } else if contains(&["not found", "does not exist", "no such session"]) {
+            FailureKind::NotFound
+        } else {
+            FailureKind::Other
+        }
+    }
+}
+
+/// `text` without URLs, e.g., `https://cas:8081/v1/sessions/NAME`
+fn without_urls(text: &str) -> String {
+    text.split_whitespace()
+        .filter(|word| !word.trim_start_matches(['(', '\'', '"', '`']).starts_with("http://") && !word.trim_start_matches(['(', '\'', '"', '`']).starts_with("https://"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// true if `text` contains `phrase` as whole words - not as part of a name like `team/unauthorized_cache`
+fn contains_phrase(text: &str, phrase: &str) -> bool {
+    let is_name_char = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '/');
+    text.match_indices(phrase).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + phrase.len()..].chars().next();
+        !before.is_some_and(is_name_char) && !after.is_some_and(is_name_char)
+    })
+}
+
+/// HTTP status reported in `text` (lower case): a code followed by its reason, e.g., `404 not found`,
+/// or preceded by `http` or `status`, e.g., `http 503` or `status code: 409`
+fn http_status(text: &str) -> Option<u16> {
+    let words : Vec<&str> = text.split(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | ':' | ',' | ';' | '[' | ']')).filter(|w| !w.is_empty()).collect();
+    words.iter().enumerate().find_map(|(i, word)| {
+        let code = word.parse::<u16>().ok().filter(|c| word.len() == 3 && (100..600).contains(c))?;
+        let reason = reqwest::StatusCode::from_u16(code).ok()?.canonical_reason()?.to_lowercase();
+        let follows = words[i + 1..].iter().take(reason.split_whitespace().count()).copied().collect::<Vec<_>>().join(" ");
+        let preceded = i > 0 && (matches!(words[i - 1], "http" | "status") || words[i - 1].starts_with("http/") || (words[i - 1] == "code" && i >= 2 && words[i - 2] == "status"));
+        (follows == reason || preceded).then_some(code)
+    })
+}
+
+/// Reason why a `scone` command failed - as determined by `CommandOutput::failure_kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    Unavailable,            // CAS or docker not reachable - transient, the command is retried
+    NotFound,               // session does not exist
+    PermissionDenied,       // we have no access to the session
+    PredecessorMismatch,    // session was updated concurrently: predecessor is not the current version
+    Other,
+}
+
+/// Errors returned by the functions of this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// docker could not be executed or the docker daemon is not reachable
+    Docker { output: CommandOutput },
+    /// the session could not be read from CAS, e.g., it does not exist or we have no access
+    SessionRead { session: String, output: CommandOutput },
+    /// CAS (or docker) was still not reachable after all retries
+    Unavailable { session: String, output: CommandOutput },
+    /// we have no access to the session
+    PermissionDenied { session: String, output: CommandOutput },
+    /// the session was updated concurrently, i.e., the predecessor is not the current version of the session
+    PredecessorMismatch { session: String, output: CommandOutput },
+    /// the session read from CAS could not be verified
+    SessionVerify { session: String, output: CommandOutput },
+    /// the rendered session was rejected by `scone session check`
+    SessionCheck { session: String, file: String, output: CommandOutput },
+    /// `scone session create` failed
+    SessionCreate { session: String, output: CommandOutput },
+    /// the session template could not be rendered
+    Template { session: String, message: String },
+    /// the session template refers to variables that are not defined - `unused` are the parameters it never refers to (see `check_template`)
+    TemplateVariables { session: String, missing: Vec<TemplateVariable>, unused: Vec<TemplateVariable> },
+    /// dependencies between sessions cannot be resolved, e.g., they are cyclic
+    Dependency { session: String, message: String },
+    /// an earlier version of the session cannot be restored, e.g., since it is not known
+    Restore { session: String, message: String },
+    /// `apply_sessions` failed to apply session `session` - the sessions in `rolled_back` were restored
+    Apply { session: String, rolled_back: Vec<String>, source: Box<Error> },
+    /// session descriptions contain cross-reference errors
+    Lint { issues: Vec<LintIssue> },
+    /// MRENCLAVE of the given image / binary could not be determined
+    MrEnclave { image: String, binary: String, output: CommandOutput },
+    /// `scone cas attest` failed, e.g., CAS does not satisfy the attestation policy
+    Attestation { cas: String, output: CommandOutput },
+    /// CAS was not attested (see `attest_cas`) or its certificate changed since the attestation
+    NotAttested { cas: String, message: String },
+    /// request to CAS failed, e.g., CAS is not reachable or the TLS handshake failed
+    Http { url: String, message: String },
+    /// CAS rejected a request
+    Cas { url: String, status: u16, body: String },
+    /// invalid CAS certificate or client identity
+    Certificate { message: String },
+    /// reading or writing a file failed
+    Io { path: String, source: io::Error },
+    /// (de)serialization of a state object failed
+    Json { context: String, source: serde_json::Error },
+    /// (de)serialization of a session description failed
+    Yaml { context: String, source: serde_yaml::Error },
+    /// an encrypted state file could not be encrypted or decrypted, e.g., due to a wrong passphrase
+    StateEncryption { path: String, message: String },
+    /// a bundle could not be written or read, e.g., due to a wrong passphrase or since it was exported by another tool
+    Bundle { path: String, message: String },
+    /// the audit log is corrupted or was tampered with at line `line` (see `verify_audit_log`)
+    AuditLog { path: String, line: usize, message: String },
+    /// the state file is locked by another process `owner`, e.g., `pid 42` - empty if not known (yet)
+    StateLocked { path: String, owner: String },
+    /// a secret could not be generated, e.g., due to an invalid alphabet
+    Secret { message: String },
+    /// the state could not be migrated from schema version `version` to the current version
+    StateMigration { path: String, version: u32, message: String },
+    /// variable `variable` of a `state.env` file cannot be imported into the state (see `import_state_env`)
+    StateEnv { variable: String, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// name of the session this error refers to - if any
+    pub fn session(&self) -> Option<&str> {
+        match self {
+            Error::SessionRead { session, .. }
+            | Error::Unavailable { session, .. }
+            | Error::PermissionDenied { session, .. }
+            | Error::PredecessorMismatch { session, .. }
+            | Error::SessionVerify { session, .. }
+            | Error::SessionCheck { session, .. }
+            | Error::SessionCreate { session, .. }
+            | Error::Template { session, .. }
+            | Error::TemplateVariables { session, .. }
+            | Error::Dependency { session, .. }
+            | Error::Restore { session, .. }
+            | Error::Apply { session, .. } => Some(session),
+            _ => None,
+        }
+    }
+
+    /// output of the underlying `scone` / `docker` invocation - if any
+    pub fn output(&self) -> Option<&CommandOutput> {
+        match self {
+            Error::Docker { output }
+            | Error::SessionRead { output, .. }
+            | Error::Unavailable { output, .. }
+            | Error::PermissionDenied { output, .. }
+            | Error::PredecessorMismatch { output, .. }
+            | Error::SessionVerify { output, .. }
+            | Error::SessionCheck { output, .. }
+            | Error::SessionCreate { output, .. }
+            | Error::MrEnclave { output, .. }
+            | Error::Attestation { output, .. } => Some(output),
+            _ => None,
+        }
+    }
+
+    /// exit code of the underlying `scone` / `docker` invocation - if any
+    pub fn exit_code(&self) -> Option<i32> {
+        self.output().map(|o| o.code)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Docker { output } => write!(f, "docker is not available (code {}): {}", output.code, output.stderr.trim()),
+            Error::SessionRead { session, output } => write!(f, "error reading session '{}' (code {}): {}", session, output.code, output.stderr.trim()),
+            Error::Unavailable { session, output } => write!(f, "CAS not available for session '{}' - giving up after retries (code {}): {}", session, output.code, output.stderr.trim()),
+            Error::PermissionDenied { session, output } => write!(f, "no access to session '{}' (code {}): {}", session, output.code, output.stderr.trim()),
+            Error::PredecessorMismatch { session, output } => write!(f, "session '{}' was updated concurrently - predecessor does not match the current version (code {}): {}", session, output.code, output.stderr.trim()),
+            Error::SessionVerify { session, output } => write!(f, "error verifying session '{}' (code {}): {}", session, output.code, output.stderr.trim()),
+            Error::SessionCheck { session, file, output } => write!(f, "session '{}' in file '{}' contains errors (code {}): {}", session, file, output.code, output.stderr.trim()),
+            Error::SessionCreate { session, output } => write!(f, "failed to create session '{}' (code {}): {}", session, output.code, output.stderr.trim()),
+            Error::Template { session, message } => write!(f, "error rendering template of session '{}': {}", session, message),
+            Error::TemplateVariables { session, missing, unused } => {
+                write!(f, "template of session '{}' refers to {} undefined variable(s):", session, missing.len())?;
+                missing.iter().try_for_each(|variable| write!(f, "\n  - {}", variable))?;
+                unused.iter().try_for_each(|variable| write!(f, "\n  - {} (unused)", variable))
+            },
+            Error::Dependency { session, message } => write!(f, "cannot order session '{}': {}", session, message),
+            Error::Restore { session, message } => write!(f, "cannot restore session '{}': {}", session, message),
+            Error::Apply { session, rolled_back, source } if rolled_back.is_empty() => write!(f, "failed to apply session '{}' - no session rolled back: {}", session, source),
+            Error::Apply { session, rolled_back, source } => write!(f, "failed to apply session '{}' - rolled back {}: {}", session, rolled_back.join(", "), source),
+            Error::Lint { issues } => {
+                write!(f, "{} problem(s) found in session descriptions:", issues.len())?;
+                issues.iter().try_for_each(|issue| write!(f, "\n  - {}", issue))
+            },
+            Error::MrEnclave { image, binary, output } => write!(f, "failed to determine MRENCLAVE of '{}' in image '{}' (code {}): {}", binary, image, output.code, output.stderr.trim()),
+            Error::Attestation { cas, output } => write!(f, "attestation of CAS '{}' failed (code {}): {}", cas, output.code, output.stderr.trim()),
+            Error::NotAttested { cas, message } => write!(f, "CAS '{}' is not attested: {}", cas, message),
+            Error::Http { url, message } => write!(f, "request {} failed: {}", url, message),
+            Error::Cas { url, status, body } => write!(f, "CAS rejected request {} (status {}): {}", url, status, body.trim()),
+            Error::Certificate { message } => write!(f, "certificate error: {}", message),
+            Error::Io { path, source } => write!(f, "error accessing '{}': {}", path, source),
+            Error::Json { context, source } => write!(f, "error (de)serializing {}: {}", context, source),
+            Error::Yaml { context, source } => write!(f, "error (de)serializing {}: {}", context, source),
+            Error::StateEncryption { path, message } => write!(f, "encrypted state '{}': {}", path, message),
+            Error::Bundle { path, message } => write!(f, "bundle '{}': {}", path, message),
+            Error::AuditLog { path, line, message } => write!(f, "audit log '{}', line {}: {}", path, line, message),
+            Error::StateLocked { path, owner } if owner.is_empty() => write!(f, "state '{}' is locked by another command - try again when this command has terminated", path),
+            Error::StateLocked { path, owner } => write!(f, "state '{}' is locked by another command ({}) - try again when this command has terminated", path, owner),
+            Error::Secret { message } => write!(f, "cannot generate secret: {}", message),
+            Error::StateMigration { path, version, message } => write!(f, "cannot migrate state '{}' from version {}: {}", path, version, message),
+            Error::StateEnv { variable, message } => write!(f, "cannot import state.env variable '{}': {}", variable, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Json { source, .. } => Some(source),
+            Error::Yaml { source, .. } => Some(source),
+            Error::Apply { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(code: i32, stdout: &str, stderr: &str) -> FailureKind {
+        CommandOutput { code, stdout: stdout.to_string(), stderr: stderr.to_string() }.failure_kind()
+    }
+
+    #[test]
+    fn failure_kinds_of_cli_errors() {
+        let cases = [
+            ("Error: Failed to read session\n\nCaused by:\n    0: CAS returned an error response: 404 Not Found\n    1: Session 'ns/otpqr-x' not found", FailureKind::NotFound),
+            ("Error: Could not read session ns/otpqr-x: session does not exist", FailureKind::NotFound),
+            ("Error: Failed to create session\n\nCaused by:\n    0: HTTP status client error (409 Conflict) for url (https://cas:8081/v1/sessions)", FailureKind::PredecessorMismatch),
+            ("Error: Failed to create session: the predecessor does not match the current version of the session", FailureKind::PredecessorMismatch),
+            ("Error: Failed to read session\n\nCaused by:\n    0: HTTP status client error (403 Forbidden) for url (https://cas:8081/v1/sessions/ns)", FailureKind::PermissionDenied),
+            ("Error: request failed with HTTP 401", FailureKind::PermissionDenied),
+            ("Error: error sending request for url (https://scone-cas.cf:8081/v1/sessions/ns): error trying to connect: tcp connect error: Connection refused (os error 111)", FailureKind::Unavailable),
+            ("Error: HTTP status server error (503 Service Unavailable) for url (https://cas:8081/v1/sessions/ns)", FailureKind::Unavailable),
+            ("Error: CAS returned status code: 502", FailureKind::Unavailable),
+            ("code 503 Service Unavailable", FailureKind::Unavailable),
+            ("code 503", FailureKind::Other),
+            ("docker: Cannot connect to the Docker daemon at unix:///var/run/docker.sock. Is the docker daemon running?", FailureKind::Unavailable),
+            ("Error: Invalid session description: missing field `services` at line 3 column 1", FailureKind::Other),
+        ];
+        for (stderr, expected) in cases {
+            assert_eq!(kind(1, "", stderr), expected, "{}", stderr);
+        }
+    }
+
+    #[test]
+    fn names_hashes_and_stdout_are_ignored() {
+        // stdout echoes the session description - with hashes and MRENCLAVEs
+        assert_eq!(kind(1, "predecessor: 4f9a503c409d\nmrenclaves: [a404b503c401d403]\ntimeout: conflict", "Error: invalid session description"), FailureKind::Other);
+        // session names and hashes in stderr
+        for stderr in [
+            "Error: invalid session description for session team/timeout-svc",
+            "Error: Failed to create session conflict-503: invalid image name",
+            "Error: invalid predecessor 4f9a503c409d404e401f403a",
+            "Error: invalid session description: unknown field in url (https://cas:8081/v1/sessions/not%20found-404)",
+            "Error: service unauthorized_cache uses an undefined volume",
+            "Error: image team/forbidden-fruit is not defined",
+        ] {
+            assert_eq!(kind(1, "", stderr), FailureKind::Other, "{}", stderr);
+        }
+        // docker cannot be executed at all
+        assert_eq!(kind(127, "", "sh: docker: not found"), FailureKind::Other);
+    }
+}
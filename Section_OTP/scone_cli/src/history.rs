@@ -0,0 +1,94 @@
+use log::{info, warn};
+use serde_yaml::Value;
+
+use crate::session::scalar_to_string;
+use crate::{session_hash, CasClient, Error, Result, Session};
+
+/// One version of a session in the predecessor chain of a session.
+#[derive(Debug, Clone)]
+pub struct SessionVersion {
+    pub hash: String,           // hash of this version
+    pub yaml: String,           // session description as stored in CAS
+    pub fields: Value,          // parsed session description
+}
+
+impl SessionVersion {
+    pub fn parse(hash: &str, yaml: &str) -> Result<Self> {
+        let fields : Value = serde_yaml::from_str(yaml).map_err(|source| Error::Yaml { context: format!("session with hash {}", hash), source })?;
+        Ok(SessionVersion { hash: hash.to_string(), yaml: yaml.to_string(), fields })
+    }
+
+    /// typed session description of this version
+    pub fn session(&self) -> Result<Session> {
+        Session::from_yaml(&self.yaml)
+    }
+
+    /// hash of the previous version - `None` for the first version of a session
+    pub fn predecessor(&self) -> Option<&str> {
+        self.fields.get("predecessor").and_then(Value::as_str)
+    }
+
+    /// version of the session description format, e.g., "0.3" - given as string or number
+    pub fn version(&self) -> Option<String> {
+        self.fields.get("version").cloned().and_then(scalar_to_string)
+    }
+
+    /// top-level fields (e.g., `secrets`, `services`) that differ from the previous version `older`
+    pub fn changed_fields(&self, older: &SessionVersion) -> Vec<String> {
+        let (Some(new), Some(old)) = (self.fields.as_mapping(), older.fields.as_mapping()) else {
+            return vec![];
+        };
+        let mut keys : Vec<String> = new.keys().chain(old.keys())
+            .filter_map(|k| k.as_str())
+            .filter(|k| *k != "predecessor" && new.get(*k) != old.get(*k))
+            .map(str::to_string)
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+}
+
+impl CasClient {
+    /// all versions of session `name`: starting with the current version, we follow the
+    /// `predecessor` hashes back to the first version.
+    pub fn session_history(&self, name: &str) -> Result<Vec<SessionVersion>> {
+        let mut hash = session_hash(name)?;
+        let mut history : Vec<SessionVersion> = vec![];
+        loop {
+            info!("Reading session {} with hash {}", name, hash);
+            let response = self.session(name, Some(&hash))?;
+            let version = SessionVersion::parse(&hash, &response.session)?;
+            let predecessor = version.predecessor().map(str::to_string);
+            history.push(version);
+            match predecessor {
+                Some(p) if history.iter().any(|v| v.hash == p) => {
+                    warn!("Predecessor chain of session {} contains a cycle at {}", name, p);
+                    break;
+                },
+                Some(p) => hash = p,
+                None => break,
+            }
+        }
+        Ok(history)
+    }
+}
+
+/// all versions of session `name` as stored in CAS `cas_addr` - see `CasClient::session_history`.
+/// Pass the address of the attested CAS, e.g., `cas_addr` of the state.
+pub fn session_history(cas_addr: &str, name: &str) -> Result<Vec<SessionVersion>> {
+    CasClient::from_cas_config(cas_addr)?.session_history(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_is_number_or_string() {
+        for yaml in ["name: s\nversion: 0.3\n", "name: s\nversion: \"0.3\"\n"] {
+            assert_eq!(SessionVersion::parse("h", yaml).unwrap().version().as_deref(), Some("0.3"), "{}", yaml);
+        }
+        assert_eq!(SessionVersion::parse("h", "name: s\n").unwrap().version(), None);
+    }
+}
@@ -1,115 +1,146 @@
-use log::{info,error};
 use serde_json::{Value};
 use serde::{Deserialize, Serialize};
-use handlebars::Handlebars;
-use shells::{sh};
-use std::fs::OpenOptions;
-use rand::{thread_rng, Rng};
-use rand::distributions::Alphanumeric;
-use std::fs;
 use std::io;
 use std::io::Write;
 
-pub fn execute_with_docker(shell: &str, cmd: &str) -> (i32, String, String) {
-    let w_prefix = &format!(r#"docker run --rm -v /var/run/docker.sock:/var/run/docker.sock -v "$HOME/.docker:/root/.docker" -v "$HOME/.cas:/root/.cas" -v "$HOME/.scone:/root/.scone" -v "$PWD:/root"     -w /root     registry.scontain.com:5050/sconecuratedimages/sconecli {}"#, cmd);
-    let mut command = {
-        let mut command = ::std::process::Command::new(shell);
-        command.arg("-c").arg(w_prefix);
-        command
-    };
-
-    match command.output() {
-        Ok(output) => {
-            (output.status.code().unwrap_or(if output.status.success() { 0 } else { 1 }),
-             String::from_utf8_lossy(&output.stdout[..]).into_owned(),
-             String::from_utf8_lossy(&output.stderr[..]).into_owned())
-        },
+mod attest;
+mod audit;
+mod bundle;
+mod cas;
+mod crypt;
+mod dryrun;
+mod env;
+mod error;
+mod history;
+mod lint;
+mod lock;
+mod measurement;
+mod namespace;
+pub mod nonblocking;
+mod orchestrate;
+mod plan;
+mod retry;
+mod runner;
+mod schema;
+mod secret;
+pub mod session;
+mod templates;
+mod tool;
+pub use attest::{AttestationPolicy, CasAttestation, Tolerate};
+pub use audit::{AuditEntry, AuditKind, audit, audit_log, record, set_audit_log, verify_audit_log, AUDIT_LOG, AUDIT_LOG_ENV};
+pub use bundle::{Bundle, BundledSession, decrypt_bundle, encrypt_bundle, is_bundle, read_bundle, write_bundle};
+pub use cas::{CasClient, SessionResponse, ValueResponse, cas_identity, CAS_PORT, CAS_SERVER_NAME, DEFAULT_CAS_ADDR};
+pub use crypt::{decrypt_state, encrypt_state, encrypt_state_file, encrypts_state, is_encrypted_state, set_state_passphrase, BUNDLE_PASSPHRASE_ENV, PASSPHRASE_ENV};
+pub use dryrun::{RenderedSession, SessionAction, dry_run_session, dry_run_sessions, write_manifest, MANIFEST};
+pub use history::{SessionVersion, session_history};
+pub use lint::{LintIssue, lint, lint_templates};
+pub use lock::{lock_state, unlock_state, release_state_locks};
+pub use measurement::{Measurement, Measurements, uses_measurement};
+pub use namespace::{Namespace, NAMESPACE_REGISTRY};
+pub use orchestrate::{AppliedSession, SessionOrder, SessionSpec, SessionUpdate, session_dependencies, session_order, session_updates};
+pub use plan::{Change, ChangeKind, SessionPlan, confirm, diff_sessions, plan_session, plan_sessions, state_values};
+pub use schema::{Migration, Migrations, Versioned, schema_version, SCHEMA_VERSION_KEY};
+pub use secret::{Secret, SecretKind, ALPHANUMERIC};
+pub use session::Session;
+pub use templates::{check_template, register_partial, template_registry, TemplateCheck, TemplateVariable};
+pub use tool::{OtpState, OtpTool, Restorable, SessionTemplates, Snapshot, TemplatesOf, restorable_versions, warn_unencrypted_snapshots, STATE_FILE};
+pub use env::{import_state_env, parse_state_env, read_state_env, STATE_ENV_FIELDS};
+pub use error::{Error, Result, CommandOutput, FailureKind};
+pub use retry::{RetryPolicy, retry_policy, set_retry_policy};
+pub use runner::{BoxFuture, CommandRunner, ContainerRunner, LocalRunner, MockRunner, runner, runner_from_name, set_runner, SCONE_CLI_IMAGE};
 
-        Err(e) => (126, String::new(), e.to_string()),
-    }
+pub fn execute_with_docker(shell: &str, cmd: &str) -> (i32, String, String) {
+    let runner = ContainerRunner { shell: shell.to_string(), ..ContainerRunner::docker() };
+    runner.run(cmd)
 }
 
-/// Macro to execute the given command using the Posix Shell.
+/// Macro to execute the given command with the SCONE CLI.
 ///
+/// The command is executed by the current `CommandRunner` (see `set_runner`).
 #[macro_export]
 macro_rules! scone {
     ( $( $cmd:tt )* ) => {{
-        $crate::execute_with_docker("sh", &format!($( $cmd )*))
+        $crate::runner().run(&format!($( $cmd )*))
     }};
 }
 
 
-pub fn create_session<'a, T : Serialize + for<'de> Deserialize<'de>>(name : &str, hash: &str, template: &str, state : &T, force: bool) -> Result<String, &'static str> {
-    // if we already know the hash of the session, we do not try to create
-    // unless we set flag force
-    if hash.is_empty() || force {
-        info!("Hash for session {} empty. Trying to determine hash.", name);
-        // we access the state object via a json "proxy" object  
-        // - we can access fields without needing to traits... but more importantly, this enables to create session for different fields
-        let mut j : Value = serde_json::from_str(&serde_json::to_string_pretty(&state).expect("Error serializing internal state")).unwrap();
-
-        let tmp_name = random_name(20);
-        let (code,stdout, stderr) = scone!("scone session read {} > {}", name, tmp_name);
-        let mut do_create = force; // create session, if force is set
-        let mut r = Err("Incorrect code");
-        if code == 0 {
-            info!("Got session {} .. verifying session now ", name);
-            let (code,stdout, stderr) = scone!("scone session verify {}", tmp_name);
-            let _ = fs::remove_file(tmp_name);
-            if code == 0 {
-                info!("OK: verified  session {}", name);
-                j["predecessor_key"] = "predecessor".into();
-                j["predecessor"] = stdout.clone().into();
-            } else {
-                error!("Error verifying session {}: {} {}", name, stdout, stderr);
-                return Err("Error reading session.")
-            }
-            r = Ok(stdout);
-        } else {
-            let _ = fs::remove_file(tmp_name);
-            do_create = true; // create session, if we cannot read session - might not yet exist
-            info!("Reading of session {} failed! Trying to create session. {} {}", name, stdout, stderr);
+/// classify a failed `scone` invocation for session `session`: docker or CAS not being reachable, missing
+/// permissions and predecessor mismatches are reported as such, all other failures are mapped via `f`.
+fn scone_error(session: &str, output: CommandOutput, f: impl FnOnce(CommandOutput) -> Error) -> Error {
+    if output.docker_unavailable() {
+        return Error::Docker { output }
+    }
+    let session = session.to_string();
+    match output.failure_kind() {
+        FailureKind::Unavailable => Error::Unavailable { session, output },
+        FailureKind::PermissionDenied => Error::PermissionDenied { session, output },
+        FailureKind::PredecessorMismatch => Error::PredecessorMismatch { session, output },
+        FailureKind::NotFound | FailureKind::Other => f(output),
+    }
+}
+
+pub fn create_session<'a, T : Serialize + for<'de> Deserialize<'de>>(name : &str, hash: &str, template: &str, state : &T, force: bool) -> Result<String> {
+    nonblocking::block_on(nonblocking::create_session(name, hash, template, state, force))
+}
+
+/// create or update the sessions of `specs` in the order of their dependencies (see `session_order`). A session is
+/// created if it does not exist and updated if `force` or its `force` flag is set or if its fingerprint changed, i.e.,
+/// the session or a session it depends on changed. If a session cannot be applied, the sessions updated before are
+/// restored to their previous descriptions and `Error::Apply` is returned. Returns hash and fingerprint of each session.
+pub fn apply_sessions<T : Serialize>(specs: &[SessionSpec], state: &T, force: bool) -> Result<Vec<AppliedSession>> {
+    nonblocking::block_on(nonblocking::apply_sessions(specs, state, force))
+}
+
+/// create the earlier session description `description` (e.g., taken from `session_history`) as a new version of
+/// session `name` - with the current version in CAS as predecessor. Returns the hash of the new version.
+pub fn restore_session(name: &str, description: &str) -> Result<String> {
+    nonblocking::block_on(nonblocking::restore_session(name, description))
+}
+
+/// read the current version of session `name` from CAS and verify it. Returns the session description
+/// and its hash - or `None` if the session cannot be read, e.g., since it does not exist yet.
+pub fn read_session(name: &str) -> Result<Option<(String, String)>> {
+    nonblocking::block_on(nonblocking::read_session(name))
+}
+
+/// render the template of session `name` with the fields of `state`. The template can refer to the hash of
+/// the current session version via `{{predecessor_key}}: {{predecessor}}` - which is commented out if there
+/// is no `predecessor`. The template can use the partials and helpers registered by `template_registry`.
+/// Fails with `Error::TemplateVariables` listing all undefined variables the template refers to - and the unused
+/// parameters, which are otherwise logged as warnings.
+pub fn render_session<T : Serialize>(name: &str, template: &str, state: &T, predecessor: Option<&str>) -> Result<String> {
+    // we access the state object via a json "proxy" object  
+    // - we can access fields without needing to traits... but more importantly, this enables to create session for different fields
+    let mut j : Value = to_json_value(state)?;
+    match predecessor {
+        Some(predecessor) => {
+            j["predecessor_key"] = "predecessor".into();
+            j["predecessor"] = predecessor.into();
+        },
+        None => {
             j["predecessor_key"] = "#".into();
             j["predecessor"] = "".into();
-        };
-        if do_create {
-            let mut reg = Handlebars::new();
-            reg.set_strict_mode(true);
-            let filename = random_name(20);
-            {
-                let f = OpenOptions::new().write(true).truncate(true).create(true).open(&filename).expect("Unable to open file");
-
-                // create session from session template and check if correct
-                let _rendered = reg.render_template_to_write(template, &j, f).expect("error rendering template");
-            }
-            let (code, _stdout, stderr) = scone!("scone session check {}", &filename);
-            if code != 0 {
-                error!("Session {}: description in '{}' contains errors: {}", &filename, name, stderr);
-                // let _ = fs::remove_file(&filename);
-                return Err("Session template seems to be incorrect - have a look at file.");
-            }
-            info!("Session template for {}: is correct.", name);
-
-            // try to create / update the session
-            let (code,stdout, stderr) = scone!("scone session create {}", &filename);
-            let _ = fs::remove_file(&filename);
-            if code == 0 {
-                info!("Created session {}: {}", name, stdout);
-                r = Ok(stdout);
-            } else {
-                info!("Creation of session {} failed: {} - see file {}", name, stderr, &filename);
-                r = Err("failed to create session.")
-            }
-        }
-        r
-    } else {
-        Ok(hash.to_string())
+        },
+    }
+    let check = check_template(template, &j);
+    if !check.missing.is_empty() {
+        return Err(Error::TemplateVariables { session: name.to_string(), missing: check.missing, unused: check.unused })
     }
+    for variable in &check.unused {
+        log::warn!("Template of session '{}' defines unused parameter {}", name, variable);
+    }
+    template_registry()?.render_template(template, &j)
+        .map_err(|e| Error::Template { session: name.to_string(), message: e.to_string() })
 }
 
-pub fn to_json_value<T : Serialize> (o : T) -> serde_json::Value {
-   let r : Value = serde_json::from_str(&serde_json::to_string_pretty(&o).expect("Error serializing Object")).expect("Error transformin to json object");
-   r
+/// hash of the current version of session `name` - as determined by `scone session verify`
+pub fn session_hash(name: &str) -> Result<String> {
+    nonblocking::block_on(nonblocking::session_hash(name))
+}
+
+pub fn to_json_value<T : Serialize> (o : T) -> Result<serde_json::Value> {
+    serde_json::to_value(o).map_err(|source| Error::Json { context: "object".to_string(), source })
 }
 
 //fn fromJsonValue<T : Serialize> (o : serde_json::Value) -> T {
@@ -117,60 +148,85 @@ pub fn to_json_value<T : Serialize> (o : T) -> serde_json::Value {
 //    state
 //}
  
-pub fn check_mrenclave<'a, T : Serialize + for<'de> Deserialize<'de>> (state: &mut T, mrenclave: &str, image: &str, binary: &str, force: bool) -> Result<(), &'static str> {
-    let mut j : Value = to_json_value(&state);
-
-    if j[mrenclave] == "" || force {
-        let (code,stdout,stderr)=sh!(r#"docker run --rm -e SCONE_HASH=1 {} {} | tr -d '[:space:]'"#, j[image], j[binary]);
-        if code == 0 {
-            info!("MrEnclave = {}, stderr={}", stdout, stderr);
-            j[mrenclave] = stdout.into();
-            *state = serde_json::from_value(j).expect("deserialization");
-            Ok(())
-        } else {
-            error!("Failed to determine MRENCLAVE: {}", stderr);
-            Err("Failed to determine MrEnclave")
-        }
-    } else {
-        Ok(())
-    }
+pub fn check_mrenclave<'a, T : Serialize + for<'de> Deserialize<'de>> (state: &mut T, mrenclave: &str, image: &str, binary: &str, force: bool) -> Result<()> {
+    nonblocking::block_on(nonblocking::check_mrenclave(state, mrenclave, image, binary, force))
 }
 
+/// determine the MRENCLAVE of each service in field `field` of the state - a map from service name to
+/// `Measurement` (see `Measurements`). A MRENCLAVE is determined if it is not yet known, if the digest of the
+/// local image differs from the digest recorded with the MRENCLAVE (i.e., the image was rebuilt), or if `force` is set.
+/// If no digest was recorded yet, the digest is recorded and the MRENCLAVE is kept.
+/// Services with the same image, binary and environment are measured only once.
+/// Returns the services whose MRENCLAVE changed - sessions that refer to them (see `uses_measurement`) must be updated.
+pub fn check_mrenclaves<T : Serialize + for<'de> Deserialize<'de>> (state: &mut T, field: &str, force: bool) -> Result<Vec<String>> {
+    nonblocking::block_on(nonblocking::check_mrenclaves(state, field, force))
+}
+
+/// attest CAS at `cas_addr` with `scone cas attest` according to `policy`. The SCONE CLI uses the attested CAS
+/// from now on. Returns the certificate of CAS and the attestation metadata - store them in the state.
+pub fn attest_cas(cas_addr: &str, policy: &AttestationPolicy) -> Result<CasAttestation> {
+    nonblocking::block_on(nonblocking::attest_cas(cas_addr, policy))
+}
+
+/// fails with `Error::NotAttested` unless `attestation` refers to CAS `cas_addr` and the SCONE CLI still uses
+/// the attested CAS, i.e., `scone cas show-certificate` returns the certificate recorded by `attest_cas`.
+/// Call this before operating on sessions.
+pub fn check_attestation(attestation: Option<&CasAttestation>, cas_addr: &str) -> Result<()> {
+    nonblocking::block_on(nonblocking::check_attestation(attestation, cas_addr))
+}
 
 pub trait Init {
     fn new() -> Self;
 }
 
-pub fn write_state<T: Serialize>(state : &T, filename : &str) {
-    let state = serde_json::to_string_pretty(&state).expect("Error serializing internal state");
-    info!("writing state {}", state);
-    fs::write(filename, state).unwrap_or_else(|_| panic!("Unable to write file '{}'", filename));
+/// write the state to file `filename` atomically - the file is locked (see `lock_state`) if not yet locked by
+/// this process. The schema version of the state (see `Versioned`) is stored in field `schema_version`.
+/// The state is encrypted if a passphrase is known (see `set_state_passphrase` and `PASSPHRASE_ENV`)
+/// or if the file is already encrypted.
+pub fn write_state<T: Serialize + Versioned>(state : &T, filename : &str) -> Result<()> {
+    nonblocking::block_on(nonblocking::write_state(state, filename))
 }
 
-pub fn read_state<T: Init + for<'de> Deserialize<'de>>(filename : &str) -> T {
-    if let Ok(state) = fs::read_to_string(filename) {
-        info!("Read state {} from {}", state, filename);
-        let state : T  = serde_json::from_str(&state).unwrap_or_else(|_| panic!("Cannot deserialize '{}'", filename));
-        state
-    } else {
-        info!("Failed to read state from file {}: creating this file now.", filename);
-        T::new()
+/// read the state from file `filename` - decrypting it if needed and migrating it to the current schema version
+/// (see `Versioned`). Returns a new state if the file does not exist.
+/// The state file stays locked (see `lock_state`) until `unlock_state` or `release_state_locks` is called.
+pub fn read_state<T: Init + Versioned + for<'de> Deserialize<'de>>(filename : &str) -> Result<T> {
+    nonblocking::block_on(nonblocking::read_state(filename))
+}
+
+/// replace the content of file `path` atomically: we write a temporary file in the same directory and rename it.
+/// Hence, after a crash, `path` contains either the old or the new content.
+pub(crate) fn write_atomic(path: &str, contents: &str) -> Result<()> {
+    let dir = std::path::Path::new(path).parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(std::path::Path::new("."));
+    let name = std::path::Path::new(path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let tmp = dir.join(format!(".{}.{}.tmp", name, random_name(8)));
+    let result = (|| {
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    })();
+    if let Err(source) = result {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(Error::Io { path: path.to_string(), source })
+    }
+    // make the rename durable
+    if let Ok(dir) = std::fs::File::open(dir) {
+        let _ = dir.sync_all();
     }
+    Ok(())
 }
 
+/// random alphanumeric name of length `len`, e.g., for namespaces and temporary files
 pub fn random_name(len : usize) -> String {
-    let rand_string: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(len)
-        .map(char::from)
-        .collect();
-    rand_string
+    // the alphabet is ASCII - hence, neither `ascii` nor `expose_str` can fail
+    Secret::ascii(len, ALPHANUMERIC).ok().and_then(|s| s.expose_str().map(str::to_string)).unwrap_or_default()
 }
 
 
-pub fn get_otp(otp: Option<String>) -> String {
+pub fn get_otp(otp: Option<String>) -> Result<String> {
     if let Some(otp) = otp {
-        otp
+        Ok(otp)
     } else {
         let prompt = r#"
         Adding a new authenticate requires an OTP from an existing authenticator.
@@ -181,10 +237,11 @@ pub fn get_otp(otp: Option<String>) -> String {
         print!("{}", prompt);
 
         // get OTP from user
-        io::stdout().flush().unwrap();
+        let stdio_error = |source| Error::Io { path: "stdin".to_string(), source };
+        io::stdout().flush().map_err(stdio_error)?;
         let mut otp = String::new();
-        io::stdin().read_line(&mut otp).expect("Error getting OTP");
+        io::stdin().read_line(&mut otp).map_err(stdio_error)?;
         otp.retain(|c| !c.is_whitespace());
-        otp
+        Ok(otp)
     }
 }
\ No newline at end of file
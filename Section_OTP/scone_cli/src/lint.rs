@@ -0,0 +1,131 @@
+use log::info;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::{check_template, render_session, to_json_value, Error, Result, Session};
+
+/// Problem found in a session description by `lint`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintIssue {
+    pub session: String,    // name of the session that contains the problem
+    pub message: String,
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.session, self.message)
+    }
+}
+
+/// Check a set of sessions offline - i.e., without CAS - for cross-reference errors:
+///  - `$$SCONE::name$$` references to undefined secrets
+///  - services referring to undefined images
+///  - images mounting undefined volumes
+///  - imports without matching export in the exporting session (if that session is part of `sessions`)
+///  - duplicate service names
+pub fn lint(sessions: &[Session]) -> Vec<LintIssue> {
+    let mut issues = vec![];
+    for session in sessions {
+        let mut issue = |message: String| issues.push(LintIssue { session: session.name.clone(), message });
+
+        let mut services = BTreeSet::new();
+        for service in &session.services {
+            if !services.insert(&service.name) {
+                issue(format!("service '{}' is defined more than once", service.name));
+            }
+            if let Some(image) = &service.image_name {
+                if session.image(image).is_none() {
+                    issue(format!("service '{}' refers to undefined image '{}'", service.name, image));
+                }
+            }
+        }
+
+        for image in &session.images {
+            for volume in &image.volumes {
+                if session.volume(&volume.name).is_none() {
+                    issue(format!("image '{}' mounts undefined volume '{}'", image.name, volume.name));
+                }
+            }
+        }
+
+        // we check all strings of the session - including fields not covered by the session model
+        if let Ok(yaml) = serde_yaml::to_string(session) {
+            for name in secret_references(&yaml) {
+                if session.secret(&name).is_none() {
+                    issue(format!("reference $$SCONE::{}$$ to undefined secret '{}'", name, name));
+                }
+            }
+        }
+
+        for secret in &session.secrets {
+            if let Some(import) = &secret.import {
+                if let Some(exporter) = sessions.iter().find(|s| s.name == import.session) {
+                    let exported = exporter.secret(&import.secret)
+                        .map(|s| s.export.iter().any(|e| e.session == session.name))
+                        .unwrap_or(false);
+                    if !exported {
+                        issue(format!("secret '{}' imports secret '{}' which session '{}' does not export to this session", secret.name, import.secret, import.session));
+                    }
+                }
+            }
+        }
+
+        for volume in &session.volumes {
+            if let Some(import) = &volume.import {
+                if let Some(exporter) = sessions.iter().find(|s| s.name == import.session) {
+                    let exported = exporter.volume(&import.volume)
+                        .map(|v| v.export.iter().any(|e| e.session == session.name))
+                        .unwrap_or(false);
+                    if !exported {
+                        issue(format!("volume '{}' imports volume '{}' which session '{}' does not export to this session", volume.name, import.volume, import.session));
+                    }
+                }
+            }
+        }
+    }
+    issues
+}
+
+/// render the session templates with the fields of `state` (without predecessor) and lint the resulting sessions.
+/// Returns `Error::Lint` with all problems found. Fields of the state that no template refers to are logged.
+pub fn lint_templates<T : Serialize>(templates: &[&str], state: &T) -> Result<()> {
+    let data = to_json_value(state)?;
+    let mut unused : Option<BTreeSet<String>> = None;
+    for template in templates {
+        let fields : BTreeSet<String> = check_template(template, &data).unused_fields.into_iter().collect();
+        unused = Some(match unused {
+            Some(unused) => unused.intersection(&fields).cloned().collect(),
+            None => fields,
+        });
+    }
+    if let Some(unused) = unused.filter(|u| !u.is_empty()) {
+        info!("State fields not used by any template: {}", unused.into_iter().collect::<Vec<_>>().join(", "));
+    }
+    let mut sessions = vec![];
+    for (i, template) in templates.iter().enumerate() {
+        let yaml = render_session(&format!("template {}", i), template, state, None)?;
+        sessions.push(Session::from_yaml(&yaml)?);
+    }
+    let issues = lint(&sessions);
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Lint { issues })
+    }
+}
+
+/// names of all secrets referenced via `$$SCONE::name$$` or `$$SCONE::name:format$$`
+fn secret_references(text: &str) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("$$SCONE::") {
+        rest = &rest[start + "$$SCONE::".len()..];
+        if let Some(end) = rest.find("$$") {
+            let name = rest[..end].split(':').next().unwrap_or_default();
+            names.insert(name.to_string());
+            rest = &rest[end + 2..];
+        }
+    }
+    names
+}
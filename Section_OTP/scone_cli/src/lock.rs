@@ -0,0 +1,112 @@
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::sync::Mutex;
+
+use crate::{Error, Result};
+
+/// lock files of the state files locked by this process - the (advisory) lock is held as long as the file is open
+static LOCKS: Mutex<BTreeMap<String, File>> = Mutex::new(BTreeMap::new());
+
+fn lock_path(filename: &str) -> String {
+    format!("{}.lock", filename)
+}
+
+/// true if `file` is (still) the file at `path` - and not a lock file that was removed by `unlock_state`
+fn is_file_at(file: &File, path: &str) -> bool {
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(f), Ok(p)) => f.dev() == p.dev() && f.ino() == p.ino(),
+        _ => false,
+    }
+}
+
+/// lock state file `filename` for this process - until `unlock_state` or `release_state_locks` is called.
+/// The lock file contains the pid of the owner while it is locked and is removed when the lock is released.
+/// A non-empty lock file that is not locked was left behind by a crashed process: this stale lock is taken over.
+pub fn lock_state(filename: &str) -> Result<()> {
+    let mut locks = LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    if locks.contains_key(filename) {
+        return Ok(())
+    }
+    let path = lock_path(filename);
+    let io_error = |source| Error::Io { path: path.clone(), source };
+    let mut file = loop {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).map_err(io_error)?;
+        match file.try_lock() {
+            // the owner might have removed the lock file between our open and lock: lock the new file instead
+            Ok(()) if is_file_at(&file, &path) => break file,
+            Ok(()) => continue,
+            Err(TryLockError::WouldBlock) => {
+                // the owner writes its pid after taking the lock - hence, the pid might not be there yet
+                let mut owner = String::new();
+                let _ = file.read_to_string(&mut owner);
+                return Err(Error::StateLocked { path: filename.to_string(), owner: owner.trim().to_string() })
+            },
+            Err(TryLockError::Error(source)) => return Err(io_error(source)),
+        }
+    };
+    let mut owner = String::new();
+    file.read_to_string(&mut owner).map_err(io_error)?;
+    if !owner.trim().is_empty() {
+        warn!("Removing stale lock of {} held by {} - the previous command did not terminate properly", filename, owner.trim());
+    }
+    file.set_len(0).map_err(io_error)?;
+    file.rewind().map_err(io_error)?;
+    write!(file, "pid {}", std::process::id()).map_err(io_error)?;
+    file.sync_all().map_err(io_error)?;
+    info!("Locked state {}", filename);
+    locks.insert(filename.to_string(), file);
+    Ok(())
+}
+
+/// release the lock of state file `filename` held by this process and remove the lock file
+pub fn unlock_state(filename: &str) {
+    let file = LOCKS.lock().unwrap_or_else(|e| e.into_inner()).remove(filename);
+    if let Some(file) = file {
+        // remove the lock file while we still hold the lock: otherwise, it could be a lock of another process
+        if let Err(e) = fs::remove_file(lock_path(filename)) {
+            warn!("Cannot remove lock file of {}: {}", filename, e);
+            // an empty lock file is not stale
+            let _ = file.set_len(0);
+        }
+        let _ = file.unlock();
+        info!("Unlocked state {}", filename);
+    }
+}
+
+/// release the locks of all state files locked by this process - call this before the process terminates
+pub fn release_state_locks() {
+    let filenames : Vec<String> = LOCKS.lock().unwrap_or_else(|e| e.into_inner()).keys().cloned().collect();
+    filenames.iter().for_each(|filename| unlock_state(filename));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn lock_file_is_removed_on_unlock() {
+        let filename = std::env::temp_dir().join(format!("lock-test-{}.js", std::process::id())).to_string_lossy().to_string();
+        lock_state(&filename).unwrap();
+        assert_eq!(fs::read_to_string(lock_path(&filename)).unwrap(), format!("pid {}", std::process::id()));
+        // another process cannot lock the state
+        let other = File::open(lock_path(&filename)).unwrap();
+        assert!(matches!(other.try_lock(), Err(TryLockError::WouldBlock)));
+        unlock_state(&filename);
+        assert!(!Path::new(&lock_path(&filename)).exists());
+        // a lock file removed by its owner is not locked: the file that is locked is the one at the path
+        assert!(other.try_lock().is_ok());
+        lock_state(&filename).unwrap();
+        assert!(is_file_at(LOCKS.lock().unwrap().get(&filename).unwrap(), &lock_path(&filename)));
+        unlock_state(&filename);
+    }
+
+    #[test]
+    fn unknown_owner_is_omitted() {
+        let error = Error::StateLocked { path: "state.js".to_string(), owner: String::new() };
+        assert_eq!(error.to_string(), "state 'state.js' is locked by another command - try again when this command has terminated");
+    }
+}
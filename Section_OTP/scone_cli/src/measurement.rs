@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::templates::partials;
+
+/// MRENCLAVE of a service: binary `binary` in image `image` started with the environment `env`.
+/// The environment matters since some variables, e.g., `SCONE_HEAP`, change the MRENCLAVE.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Measurement {
+    pub image: String,                      // container image, e.g., otpqr:scone
+    pub binary: String,                     // binary inside the image, e.g., /bin/otpqr
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,      // environment the service runs with, e.g., SCONE_HEAP=1G
+    #[serde(default)]
+    pub mrenclave: String,                  // empty until determined by `check_mrenclaves`
+    #[serde(default)]
+    pub digest: String,                     // digest (image ID) of the image the MRENCLAVE was determined from
+}
+
+impl Measurement {
+    /// measurement of `binary` in `image` with an empty environment - the MRENCLAVE is not yet known
+    pub fn new(image: &str, binary: &str) -> Self {
+        Measurement { image: image.to_string(), binary: binary.to_string(), ..Measurement::default() }
+    }
+
+    /// set environment variable `name` to `value`
+    pub fn with_env(mut self, name: &str, value: &str) -> Self {
+        self.env.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// true if measurement `other` has the same image, binary and environment - and hence the same MRENCLAVE
+    pub fn same_target(&self, other: &Measurement) -> bool {
+        self.image == other.image && self.binary == other.binary && self.env == other.env
+    }
+}
+
+/// measurements of the services of a session - indexed by the name of the service.
+/// A template refers to the MRENCLAVE of service `sign` with `{{mrenclaves.sign.mrenclave}}`.
+pub type Measurements = BTreeMap<String, Measurement>;
+
+/// true if `template` - or a partial it includes - refers to the measurement of `service` in state field `field`,
+/// e.g., `{{mrenclaves.sign.mrenclave}}`. A session rendered from such a template must be updated whenever
+/// the MRENCLAVE of the service changes.
+pub fn uses_measurement(template: &str, field: &str, service: &str) -> bool {
+    let partials = partials();
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![template.to_string()];
+    while let Some(template) = pending.pop() {
+        if template.contains(&format!("{}.{}.", field, service)) || template.contains(&format!("{}.[{}].", field, service)) {
+            return true
+        }
+        for (name, partial) in partials.iter() {
+            if includes_partial(&template, name) && visited.insert(name.clone()) {
+                pending.push(partial.clone());
+            }
+        }
+    }
+    false
+}
+
+/// true if `template` contains `{{> name}}` or `{{> name param=...}}`
+fn includes_partial(template: &str, name: &str) -> bool {
+    template.match_indices("{{>").any(|(i, _)| {
+        let rest = template[i + 3..].trim_start().trim_start_matches('~');
+        rest.strip_prefix(name).is_some_and(|r| r.starts_with(|c: char| c.is_whitespace() || c == '}' || c == '~'))
+    })
+}
@@ -0,0 +1,147 @@
+use log::info;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::session::AccessPolicy;
+use crate::{create_session, lock_state, random_name, read_session, unlock_state, Error, Result, Session};
+
+/// file (relative to `$HOME`) in which we keep track of the namespaces and sessions created in each namespace
+pub const NAMESPACE_REGISTRY: &str = ".scone/namespaces.json";
+
+/// Hierarchical namespace, e.g., `team/project/env`: `ensure` creates the namespace and all its parent
+/// namespaces in CAS. CAS does not list the sessions of a namespace. Hence, we keep track of the sessions
+/// created in a namespace in a local registry (see `NAMESPACE_REGISTRY`).
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    pub path: String,                   // name of the namespace session, e.g., team/project/env
+    pub access_policy: AccessPolicy,    // access policy of namespaces created by `ensure` - default: CREATOR only
+    pub registry: PathBuf,              // registry of the children of namespaces
+}
+
+impl Namespace {
+    /// namespace `path` that only the creator can access
+    pub fn new(path: &str) -> Self {
+        Namespace {
+            path: path.trim_matches('/').to_string(),
+            access_policy: AccessPolicy::creator(),
+            registry: default_registry(),
+        }
+    }
+
+    /// use `access_policy` instead of the CREATOR-only policy
+    pub fn with_access_policy(mut self, access_policy: AccessPolicy) -> Self {
+        self.access_policy = access_policy;
+        self
+    }
+
+    /// use registry file `registry` instead of `$HOME/.scone/namespaces.json`
+    pub fn with_registry(mut self, registry: &str) -> Self {
+        self.registry = PathBuf::from(registry);
+        self
+    }
+
+    /// last component of the path, e.g., `env` for `team/project/env`
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or_default()
+    }
+
+    /// parent namespace - `None` for top-level namespaces
+    pub fn parent(&self) -> Option<Namespace> {
+        self.path.rsplit_once('/').map(|(parent, _)| Namespace { path: parent.to_string(), ..self.clone() })
+    }
+
+    /// paths of this namespace and all its parents - starting with the top-level namespace
+    pub fn ancestors(&self) -> Vec<String> {
+        let components : Vec<&str> = self.path.split('/').collect();
+        (1..=components.len()).map(|n| components[..n].join("/")).collect()
+    }
+
+    /// full name of session `name` in this namespace
+    pub fn session_name(&self, name: &str) -> String {
+        format!("{}/{}", self.path, name.trim_matches('/'))
+    }
+
+    /// session description of namespace `path` with the access policy of this namespace
+    fn description(&self, path: &str) -> Session {
+        Session { access_policy: Some(self.access_policy.clone()), ..Session::new(path) }
+    }
+
+    /// create this namespace and all missing parent namespaces. Existing namespaces are not modified.
+    /// Returns the hash of each namespace - starting with the top-level namespace.
+    pub fn ensure(&self) -> Result<Vec<String>> {
+        let mut hashes = vec![];
+        for path in self.ancestors() {
+            let yaml = self.description(&path).to_yaml()?;
+            let hash = create_session(&path, "", &yaml, &serde_json::json!({}), false)?;
+            info!("Namespace {} has hash {}", path, hash.trim());
+            let parent = path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or_default();
+            self.update_registry(|registry| {
+                registry.entry(parent.to_string()).or_default().insert(path.clone());
+            })?;
+            hashes.push(hash.trim().to_string());
+        }
+        Ok(hashes)
+    }
+
+    /// sessions and namespaces created (or reserved) in this namespace - as recorded in the registry
+    pub fn children(&self) -> Result<Vec<String>> {
+        let registry = read_registry(&self.registry)?;
+        Ok(registry.get(&self.path).map(|c| c.iter().cloned().collect()).unwrap_or_default())
+    }
+
+    /// record that session `name` (without namespace) was created in this namespace
+    pub fn register(&self, name: &str) -> Result<()> {
+        let session = self.session_name(name);
+        self.update_registry(|registry| {
+            registry.entry(self.path.clone()).or_default().insert(session);
+        })
+    }
+
+    /// reserve a new session name in this namespace: `prefix` followed by a random suffix that is neither
+    /// recorded in the registry nor used by a session in CAS. The reserved name is recorded in the registry.
+    pub fn reserve_name(&self, prefix: &str) -> Result<String> {
+        loop {
+            let name = format!("{}{}", prefix, random_name(12));
+            let session = self.session_name(&name);
+            if self.children()?.contains(&session) || read_session(&session)?.is_some() {
+                info!("Session name {} is already taken", session);
+                continue;
+            }
+            self.register(&name)?;
+            return Ok(session)
+        }
+    }
+
+    fn update_registry(&self, update: impl FnOnce(&mut Registry)) -> Result<()> {
+        let path = self.registry.display().to_string();
+        if let Some(dir) = self.registry.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|source| Error::Io { path: dir.display().to_string(), source })?;
+        }
+        lock_state(&path)?;
+        let result = read_registry(&self.registry).and_then(|mut registry| {
+            update(&mut registry);
+            let json = serde_json::to_string_pretty(&registry).map_err(|source| Error::Json { context: "namespace registry".to_string(), source })?;
+            crate::write_atomic(&path, &json)
+        });
+        unlock_state(&path);
+        result
+    }
+}
+
+/// children of each namespace - top-level namespaces are children of ""
+type Registry = BTreeMap<String, BTreeSet<String>>;
+
+fn default_registry() -> PathBuf {
+    env::var("HOME").map(PathBuf::from).unwrap_or_default().join(NAMESPACE_REGISTRY)
+}
+
+fn read_registry(path: &Path) -> Result<Registry> {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).map_err(|source| Error::Json { context: format!("namespace registry '{}'", path.display()), source }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Registry::new()),
+        Err(source) => Err(Error::Io { path: path.display().to_string(), source }),
+    }
+}
@@ -0,0 +1,635 @@
+//! Async variants of the functions of this crate that execute SCONE CLI commands or access state files.
+//!
+//! Commands are executed with `CommandRunner::run_async`, i.e., `ContainerRunner` and `LocalRunner` spawn
+//! tokio child processes. Locking, passphrase prompts and key derivation of the state files run on the blocking
+//! threads of the runtime. These functions must be called from within a tokio runtime. The sync functions
+//! of the crate root are thin wrappers that run these functions on a new current-thread runtime - they fail if
+//! called from within a runtime.
+
+use log::{info, warn, error};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use tokio::fs;
+use zeroize::Zeroizing;
+
+use crate::{audit, crypt, orchestrate, session_updates, AuditEntry, AuditKind, AppliedSession, AttestationPolicy, CasAttestation, lock_state, retry, random_name, render_session, runner, schema, scone_error, to_json_value,
+    CommandOutput, Error, FailureKind, Init, Measurement, Measurements, Result, Session, SessionAction, SessionSpec, SessionUpdate, Versioned};
+
+/// run `future` to completion on a new current-thread runtime - used by the sync API.
+/// Fails if called from within a tokio runtime: nesting runtimes panics.
+pub(crate) fn block_on<T>(future: impl Future<Output = Result<T>>) -> Result<T> {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        let message = "sync function of scone_cli called from within a tokio runtime - use the function of module nonblocking";
+        return Err(Error::Io { path: "tokio runtime".to_string(), source: io::Error::other(message) })
+    }
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|source| Error::Io { path: "tokio runtime".to_string(), source })?
+        .block_on(future)
+}
+
+/// execute `cmd` with the current runner - retrying transient failures (see `RetryPolicy`).
+/// Only for commands that can be repeated safely, i.e., that do not modify sessions - see `create`.
+pub(crate) async fn scone(cmd: String) -> CommandOutput {
+    let cmd = &cmd;
+    retry::retry(cmd, || async move { runner().run_async(cmd).await.into() }).await
+}
+
+/// `scone session create` of the description `session` of session `name` in file `filename`. A transient failure
+/// is not simply retried: CAS might have applied the request before it failed. Hence, we read the session again and
+/// retry only if it is still at its predecessor (or still does not exist). If it already contains `session`, the
+/// create succeeded. If it contains another description, the session was updated concurrently.
+async fn create(name: &str, session: &str, filename: &str) -> Result<CommandOutput> {
+    let policy = retry::retry_policy();
+    let mut attempt = 1;
+    loop {
+        let output : CommandOutput = runner().run_async(&format!("scone session create {}", filename)).await.into();
+        if output.code == 0 || output.failure_kind() != FailureKind::Unavailable || attempt >= policy.max_attempts {
+            return Ok(output)
+        }
+        let mut expected = Session::from_yaml(session)?;
+        let predecessor = expected.predecessor.take();
+        match try_read_session(name).await {
+            Ok(Err(_)) if predecessor.is_none() => {},
+            Ok(Ok((_, hash))) if predecessor.as_deref().map(str::trim) == Some(hash.trim()) => {},
+            Ok(Ok((yaml, hash))) => {
+                let mut current = Session::from_yaml(&yaml)?;
+                current.predecessor = None;
+                if current == expected {
+                    info!("Session {} was created although 'scone session create' failed: {}", name, output.stderr.trim());
+                    return Ok(CommandOutput { code: 0, stdout: hash, stderr: output.stderr })
+                }
+                return Err(Error::PredecessorMismatch { session: name.to_string(), output })
+            },
+            // we cannot tell whether the create was applied - we must not retry
+            Ok(Err(_)) | Err(_) => return Ok(output),
+        }
+        let delay = policy.delay(attempt);
+        warn!("Creating session {} failed (attempt {} of {}): {} - session is unchanged, retrying in {:?}", name, attempt, policy.max_attempts, output.stderr.trim(), delay);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// see `scone_cli::create_session`
+pub async fn create_session<T : Serialize>(name : &str, hash: &str, template: &str, state : &T, force: bool) -> Result<String> {
+    // if we already know the hash of the session, we do not try to create
+    // unless we set flag force
+    if hash.is_empty() || force {
+        info!("Hash for session {} empty. Trying to determine hash.", name);
+
+        let current = read_session(name).await?;
+        apply_session(name, current.as_ref(), template, state, force).await
+    } else {
+        Ok(hash.to_string())
+    }
+}
+
+/// create / update session `name` whose current version (description and hash) is `current` - if `force` is set or
+/// if the session does not exist yet. Returns the hash of the current version.
+async fn apply_session<T : Serialize>(name: &str, current: Option<&(String, String)>, template: &str, state: &T, force: bool) -> Result<String> {
+    match current {
+        Some((_, hash)) if !force => Ok(hash.clone()),
+        _ => {
+            // create session from session template and check if correct
+            let session = render_session(name, template, state, current.map(|(_, hash)| hash.as_str()))?;
+            submit_session(name, session).await
+        },
+    }
+}
+
+/// check the session description `session` of session `name` and create / update the session. Returns the new hash.
+async fn submit_session(name: &str, session: String) -> Result<String> {
+    let filename = random_name(20);
+    fs::write(&filename, &session).await.map_err(|source| Error::Io { path: filename.clone(), source })?;
+    let output = scone(format!("scone session check {}", &filename)).await;
+    if output.code != 0 {
+        error!("Session {}: description in '{}' contains errors: {}", &filename, name, output.stderr);
+        // let _ = fs::remove_file(&filename);
+        return Err(scone_error(name, output, |output| Error::SessionCheck { session: name.to_string(), file: filename.clone(), output }));
+    }
+    info!("Session template for {}: is correct.", name);
+
+    // try to create / update the session
+    let output = create(name, &session, &filename).await;
+    let _ = fs::remove_file(&filename).await;
+    let output = output.inspect_err(|_| audit::record(AuditEntry::new(AuditKind::SessionCreate, name).with_success(false)))?;
+    audit::record(AuditEntry::new(AuditKind::SessionCreate, name).with_hash(if output.code == 0 { &output.stdout } else { "" }).with_success(output.code == 0));
+    if output.code == 0 {
+        info!("Created session {}: {}", name, output.stdout);
+        Ok(output.stdout)
+    } else {
+        info!("Creation of session {} failed: {} - see file {}", name, output.stderr, &filename);
+        Err(scone_error(name, output, |output| Error::SessionCreate { session: name.to_string(), output }))
+    }
+}
+
+/// see `scone_cli::restore_session`
+pub async fn restore_session(name: &str, description: &str) -> Result<String> {
+    let predecessor = session_hash(name).await?;
+    info!("Restoring an earlier version of session {} - predecessor {}", name, predecessor);
+    resubmit_session(name, description, &predecessor).await
+}
+
+/// create `description` - an earlier description of session `name` - as new version with predecessor `predecessor`
+async fn resubmit_session(name: &str, description: &str, predecessor: &str) -> Result<String> {
+    let mut session = Session::from_yaml(description)?;
+    session.predecessor = Some(predecessor.trim().to_string());
+    submit_session(name, session.to_yaml()?).await
+}
+
+/// see `scone_cli::apply_sessions`
+pub async fn apply_sessions<T : Serialize>(specs: &[SessionSpec], state: &T, force: bool) -> Result<Vec<AppliedSession>> {
+    let mut applied = vec![];
+    let mut undo = vec![];     // applied changes: session, previous description (if any) and new hash
+    for SessionUpdate { name, fingerprint, update } in session_updates(specs, state, force)? {
+        let spec = orchestrate::spec(specs, &name);
+        if !update && !spec.hash.is_empty() {
+            info!("Session {} is unchanged", name);
+            applied.push(AppliedSession { session: name, hash: spec.hash.clone(), fingerprint, action: SessionAction::Unchanged });
+            continue;
+        }
+        let result = async {
+            let previous = read_session(&name).await?;
+            let hash = apply_session(&name, previous.as_ref(), &spec.template, state, update).await?;
+            Ok::<_, Error>((previous, hash.trim().to_string()))
+        }.await;
+        match result {
+            Ok((previous, hash)) => {
+                let action = match &previous {
+                    None => SessionAction::Create,
+                    Some((_, current)) if current.trim() == hash => SessionAction::Unchanged,
+                    Some(_) => SessionAction::Update,
+                };
+                info!("Session {}: {:?} - hash {}", name, action, hash);
+                if action != SessionAction::Unchanged {
+                    undo.push((name.clone(), previous.map(|(yaml, _)| yaml), hash.clone()));
+                }
+                applied.push(AppliedSession { session: name, hash, fingerprint, action });
+            },
+            Err(source) => {
+                error!("Applying session {} failed - rolling back {} session(s)", name, undo.len());
+                let rolled_back = roll_back(undo).await;
+                return Err(Error::Apply { session: name, rolled_back, source: Box::new(source) })
+            },
+        }
+    }
+    Ok(applied)
+}
+
+/// restore the previous descriptions of the applied sessions - in reverse order. Returns the restored sessions.
+/// Sessions that did not exist before cannot be removed from CAS - they are kept.
+async fn roll_back(undo: Vec<(String, Option<String>, String)>) -> Vec<String> {
+    let mut rolled_back = vec![];
+    for (name, previous, hash) in undo.into_iter().rev() {
+        let Some(previous) = previous else {
+            warn!("Cannot roll back session {}: it was created and CAS does not remove sessions", name);
+            continue;
+        };
+        match resubmit_session(&name, &previous, &hash).await {
+            Ok(hash) => {
+                info!("Rolled back session {}: hash {}", name, hash.trim());
+                rolled_back.push(name);
+            },
+            Err(e) => error!("Cannot roll back session {}: {}", name, e),
+        }
+    }
+    rolled_back
+}
+
+/// see `scone_cli::read_session`
+pub async fn read_session(name: &str) -> Result<Option<(String, String)>> {
+    Ok(try_read_session(name).await?.ok())
+}
+
+/// like `read_session` but returns the output of `scone session read` if the session cannot be read
+pub(crate) async fn try_read_session(name: &str) -> Result<std::result::Result<(String, String), CommandOutput>> {
+    let tmp_name = random_name(20);
+    let output = scone(format!("scone session read {} > {}", name, tmp_name)).await;
+    if output.code != 0 {
+        let _ = fs::remove_file(tmp_name).await;
+        return match output.failure_kind() {
+            FailureKind::NotFound if !output.docker_unavailable() => {
+                info!("Session {} does not exist: {} {}", name, output.stdout, output.stderr);
+                Ok(Err(output))
+            },
+            _ => {
+                error!("Cannot read session {}: {}", name, output.stderr);
+                Err(scone_error(name, output, |output| Error::SessionRead { session: name.to_string(), output }))
+            },
+        }
+    }
+    info!("Got session {} .. verifying session now ", name);
+    let yaml = fs::read_to_string(&tmp_name).await.unwrap_or_default();
+    let output = scone(format!("scone session verify {}", tmp_name)).await;
+    let _ = fs::remove_file(tmp_name).await;
+    if output.code != 0 {
+        error!("Error verifying session {}: {} {}", name, output.stdout, output.stderr);
+        return Err(scone_error(name, output, |output| Error::SessionVerify { session: name.to_string(), output }))
+    }
+    info!("OK: verified  session {}", name);
+    Ok(Ok((yaml, output.stdout)))
+}
+
+/// see `scone_cli::session_hash`
+pub async fn session_hash(name: &str) -> Result<String> {
+    match try_read_session(name).await? {
+        Ok((_, hash)) => Ok(hash.trim().to_string()),
+        Err(output) => Err(Error::SessionRead { session: name.to_string(), output }),
+    }
+}
+
+/// see `scone_cli::check_mrenclave`
+pub async fn check_mrenclave<T : Serialize + for<'de> Deserialize<'de>> (state: &mut T, mrenclave: &str, image: &str, binary: &str, force: bool) -> Result<()> {
+    let mut j : Value = to_json_value(&state)?;
+
+    if j[mrenclave] == "" || force {
+        let image_name = j[image].as_str().unwrap_or_default().to_string();
+        let binary_name = j[binary].as_str().unwrap_or_default().to_string();
+        j[mrenclave] = measure(&image_name, &binary_name, &BTreeMap::new()).await?.into();
+        *state = serde_json::from_value(j).map_err(|source| Error::Json { context: "state".to_string(), source })?;
+    }
+    Ok(())
+}
+
+/// see `scone_cli::check_mrenclaves`
+pub async fn check_mrenclaves<T : Serialize + for<'de> Deserialize<'de>> (state: &mut T, field: &str, force: bool) -> Result<Vec<String>> {
+    let mut j : Value = to_json_value(&state)?;
+    let mut measurements : Measurements = serde_json::from_value(j[field].clone())
+        .map_err(|source| Error::Json { context: format!("field '{}' of state", field), source })?;
+
+    let mut digests : BTreeMap<String, String> = BTreeMap::new();
+    let mut measured : Vec<Measurement> = vec![];
+    let mut changed = vec![];
+    let mut recorded = false;     // digests recorded without measuring again
+    for (service, m) in measurements.iter_mut() {
+        if !digests.contains_key(&m.image) {
+            digests.insert(m.image.clone(), image_digest(&m.image).await);
+        }
+        let digest = digests[&m.image].clone();
+        // states migrated from a version without digests have no digest yet: this is no drift
+        let drifted = !m.mrenclave.is_empty() && !digest.is_empty() && !m.digest.is_empty() && digest != m.digest;
+        if !m.mrenclave.is_empty() && !force && !drifted {
+            if digest.is_empty() {
+                info!("Cannot determine digest of image {}: keeping MRENCLAVE of service {}", m.image, service);
+            } else if m.digest.is_empty() {
+                info!("Recording digest {} of image {} of service {}", digest, m.image, service);
+                m.digest = digest;
+                recorded = true;
+            }
+            continue
+        }
+        if drifted {
+            warn!("Image {} of service {} changed ({} -> {}): determining MRENCLAVE again", m.image, service, m.digest, digest);
+        }
+        // services with the same image, binary and environment share the MRENCLAVE
+        let mrenclave = match measured.iter().find(|done| done.same_target(m)) {
+            Some(done) => done.mrenclave.clone(),
+            None => measure(&m.image, &m.binary, &m.env).await?,
+        };
+        if !m.mrenclave.is_empty() && m.mrenclave != mrenclave {
+            warn!("MRENCLAVE of service {} changed: {} -> {}", service, m.mrenclave, mrenclave);
+            changed.push(service.clone());
+        }
+        info!("MrEnclave of service {} = {}", service, mrenclave);
+        m.mrenclave = mrenclave;
+        // the image might have been pulled by `measure`
+        m.digest = if digest.is_empty() { image_digest(&m.image).await } else { digest };
+        digests.insert(m.image.clone(), m.digest.clone());
+        measured.push(m.clone());
+    }
+    if measured.is_empty() && !recorded {
+        return Ok(changed)
+    }
+    j[field] = to_json_value(&measurements)?;
+    *state = serde_json::from_value(j).map_err(|source| Error::Json { context: "state".to_string(), source })?;
+    Ok(changed)
+}
+
+/// digest of the local copy of `image` - empty if it cannot be determined, e.g., since the image was not yet pulled
+async fn image_digest(image: &str) -> String {
+    let (code, stdout, stderr) = runner().image_digest_async(image).await;
+    if code != 0 {
+        info!("Cannot inspect image {}: {}", image, stderr.trim());
+        return String::new()
+    }
+    stdout.trim().to_string()
+}
+
+/// MRENCLAVE of `binary` in `image` started with environment `env`
+async fn measure(image: &str, binary: &str, env: &BTreeMap<String, String>) -> Result<String> {
+    let mut vars : Vec<(&str, &str)> = env.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    vars.push(("SCONE_HASH", "1"));
+    let vars = &vars;
+    let what = format!("MRENCLAVE of {} in {}", binary, image);
+    let mut output = retry::retry(&what, || async move { runner().run_image_async(image, binary, vars).await.into() }).await;
+    output.stdout.retain(|c| !c.is_whitespace());
+    if output.code == 0 && !output.stdout.is_empty() {
+        info!("MrEnclave = {}, stderr={}", output.stdout, output.stderr);
+        Ok(output.stdout)
+    } else {
+        error!("Failed to determine MRENCLAVE: {}", output.stderr);
+        Err(Error::MrEnclave { image: image.to_string(), binary: binary.to_string(), output })
+    }
+}
+
+/// see `scone_cli::attest_cas`
+pub async fn attest_cas(cas_addr: &str, policy: &AttestationPolicy) -> Result<CasAttestation> {
+    if let Some(problem) = policy.problem() {
+        return Err(Error::NotAttested { cas: cas_addr.to_string(), message: format!("invalid attestation policy: {}", problem) })
+    }
+    let output = scone(format!("scone cas attest {} {}", policy.options(), cas_addr)).await;
+    audit::record(AuditEntry::new(AuditKind::CasAttest, cas_addr).with_detail(&policy.options()).with_success(output.code == 0));
+    if output.code != 0 {
+        error!("Attestation of CAS {} failed: {}", cas_addr, output.stderr);
+        return Err(if output.docker_unavailable() { Error::Docker { output } } else { Error::Attestation { cas: cas_addr.to_string(), output } })
+    }
+    info!("Attested CAS {}: {}", cas_addr, output.stdout.trim());
+    Ok(CasAttestation {
+        cas_addr: cas_addr.to_string(),
+        policy: policy.clone(),
+        certificate: cas_certificate(cas_addr).await?,
+        attested_at: time::now_utc().rfc3339().to_string(),
+    })
+}
+
+/// see `scone_cli::check_attestation`
+pub async fn check_attestation(attestation: Option<&CasAttestation>, cas_addr: &str) -> Result<()> {
+    let not_attested = |message: &str| Error::NotAttested { cas: cas_addr.to_string(), message: message.to_string() };
+    let attestation = attestation.ok_or_else(|| not_attested("no attestation recorded - attest CAS first"))?;
+    if attestation.cas_addr != cas_addr {
+        return Err(not_attested(&format!("recorded attestation is for CAS '{}'", attestation.cas_addr)))
+    }
+    if cas_certificate(cas_addr).await?.trim() != attestation.certificate.trim() {
+        return Err(not_attested("certificate of CAS changed since the attestation - attest CAS again"))
+    }
+    info!("CAS {} was attested at {}", cas_addr, attestation.attested_at);
+    Ok(())
+}
+
+/// certificate of the CAS attested by the SCONE CLI
+async fn cas_certificate(cas_addr: &str) -> Result<String> {
+    let output = scone("scone cas show-certificate".to_string()).await;
+    if output.code != 0 || output.stdout.trim().is_empty() {
+        return Err(if output.docker_unavailable() { Error::Docker { output } } else { Error::NotAttested { cas: cas_addr.to_string(), message: format!("cannot retrieve CAS certificate: {}", output.stderr.trim()) } })
+    }
+    Ok(output.stdout)
+}
+
+/// see `scone_cli::write_state`
+pub async fn write_state<T: Serialize + Versioned>(state : &T, filename : &str) -> Result<()> {
+    let mut state = to_json_value(state)?;
+    schema::set_schema_version(&mut state, T::SCHEMA_VERSION);
+    let state = Zeroizing::new(serde_json::to_string_pretty(&state).map_err(|source| Error::Json { context: format!("state for '{}'", filename), source })?);
+    let path = filename.to_string();
+    // locking, asking for the passphrase and deriving the key block
+    blocking(move || {
+        lock_state(&path)?;
+        let encrypted = std::fs::read_to_string(&path).map(|text| crypt::is_encrypted_state(&text)).unwrap_or(false);
+        let state = if encrypted || crypt::known_passphrase().is_some() {
+            info!("writing encrypted state to {}", path);
+            Zeroizing::new(crypt::encrypt_state(&state, &crypt::state_passphrase(&path, false)?, &path)?)
+        } else {
+            warn!("writing state to {} unencrypted - consider encrypting it", path);
+            state
+        };
+        crate::write_atomic(&path, &state)
+    }).await
+}
+
+/// see `scone_cli::read_state`
+pub async fn read_state<T: Init + Versioned + for<'de> Deserialize<'de>>(filename : &str) -> Result<T> {
+    let path = filename.to_string();
+    let state = blocking(move || {
+        lock_state(&path)?;
+        let state = match std::fs::read_to_string(&path) {
+            Ok(state) => Zeroizing::new(state),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => return Err(Error::Io { path: path.clone(), source }),
+        };
+        if crypt::is_encrypted_state(&state) {
+            info!("Read encrypted state from {}", path);
+            crypt::decrypt_state(&state, &crypt::state_passphrase(&path, false)?, &path).map(Some)
+        } else {
+            info!("Read state from {}", path);
+            Ok(Some(state))
+        }
+    }).await?;
+    let Some(state) = state else {
+        info!("State file {} does not exist: creating this file now.", filename);
+        return Ok(T::new())
+    };
+    let state : Value = serde_json::from_str(&state).map_err(|source| Error::Json { context: format!("state in '{}'", filename), source })?;
+    schema::upgrade(state, filename)
+}
+
+/// run the blocking function `f` - e.g., file locking, passphrase prompts or key derivation - on the
+/// blocking threads of the runtime. A panic of `f` is resumed.
+pub(crate) async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => match e.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            Err(e) => Err(Error::Io { path: "blocking task".to_string(), source: io::Error::other(e) }),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{set_audit_log, set_retry_policy, set_runner, MockRunner, RetryPolicy};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // the runner, the retry policy and the audit log are global: the tests must not run concurrently
+    static RUNNER: Mutex<()> = Mutex::new(());
+
+    const TEMPLATE: &str = "name: {{session}}\nversion: \"0.3\"\n{{predecessor_key}}: {{predecessor}}\n";
+    const TEMPLATE2: &str = "name: {{session2}}\nversion: \"0.3\"\n{{predecessor_key}}: {{predecessor}}\n";
+    const NOT_FOUND: &str = "Error: Failed to read session\n\nCaused by:\n    0: CAS returned an error response: 404 Not Found";
+    const UNAVAILABLE: &str = "Error: HTTP status server error (503 Service Unavailable) for url (https://cas:8081/v1/sessions)";
+    const CONFLICT: &str = "Error: Failed to create session\n\nCaused by:\n    0: HTTP status client error (409 Conflict) for url (https://cas:8081/v1/sessions)";
+
+    fn mock_runner() -> Arc<MockRunner> {
+        let mock = Arc::new(MockRunner::new());
+        set_runner(mock.clone());
+        set_retry_policy(RetryPolicy { max_attempts: 3, initial_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1), multiplier: 1.0 });
+        set_audit_log(&std::env::temp_dir().join(format!("scone_cli_test_{}.log", std::process::id())).to_string_lossy());
+        mock
+    }
+
+    fn count(mock: &MockRunner, prefix: &str) -> usize {
+        mock.commands().iter().filter(|cmd| cmd.starts_with(prefix)).count()
+    }
+
+    #[test]
+    fn reading_sessions() {
+        let _guard = RUNNER.lock().unwrap_or_else(|e| e.into_inner());
+        let mock = mock_runner();
+        mock.respond("scone session read ns/missing", 1, "", NOT_FOUND);
+        assert_eq!(block_on(try_read_session("ns/missing")).unwrap().unwrap_err().code, 1);
+        assert_eq!(block_on(read_session("ns/missing")).unwrap(), None);
+
+        // unavailable, then success
+        mock.respond_once("scone session read ns/s", 1, "", UNAVAILABLE);
+        mock.respond("scone session read ns/s", 0, "name: ns/s\nversion: \"0.3\"\n", "");
+        mock.respond("scone session verify", 0, "h1\n", "");
+        assert_eq!(block_on(try_read_session("ns/s")).unwrap().unwrap(), ("name: ns/s\nversion: \"0.3\"\n".to_string(), "h1\n".to_string()));
+        assert_eq!(count(&mock, "scone session read ns/s "), 2);
+
+        // unavailable until the attempts are used up
+        mock.respond("scone session read ns/down", 1, "", UNAVAILABLE);
+        assert!(matches!(block_on(try_read_session("ns/down")), Err(Error::Unavailable { .. })));
+        assert_eq!(count(&mock, "scone session read ns/down "), 3);
+    }
+
+    #[test]
+    fn creating_sessions() {
+        let _guard = RUNNER.lock().unwrap_or_else(|e| e.into_inner());
+        let state = json!({ "session": "ns/s" });
+
+        // not found: the session is created
+        let mock = mock_runner();
+        mock.respond("scone session read ns/s", 1, "", NOT_FOUND);
+        mock.respond("scone session create", 0, "h1\n", "");
+        assert_eq!(block_on(create_session("ns/s", "", TEMPLATE, &state, false)).unwrap().trim(), "h1");
+        // a known hash is not checked unless forced
+        assert_eq!(block_on(create_session("ns/s", "h0", TEMPLATE, &state, false)).unwrap(), "h0");
+        assert_eq!(count(&mock, "scone session create"), 1);
+
+        // unavailable and the session still does not exist: create again
+        let mock = mock_runner();
+        mock.respond("scone session read ns/s", 1, "", NOT_FOUND);
+        mock.respond_once("scone session create", 1, "", UNAVAILABLE);
+        mock.respond("scone session create", 0, "h1\n", "");
+        assert_eq!(block_on(create_session("ns/s", "", TEMPLATE, &state, false)).unwrap().trim(), "h1");
+        assert_eq!(count(&mock, "scone session create"), 2);
+
+        // unavailable but CAS applied the create: do not create again
+        let mock = mock_runner();
+        mock.respond_once("scone session read ns/s", 1, "", NOT_FOUND);
+        mock.respond("scone session read ns/s", 0, "name: ns/s\nversion: '0.3'\n", "");
+        mock.respond("scone session verify", 0, "h1\n", "");
+        mock.respond("scone session create", 1, "", UNAVAILABLE);
+        assert_eq!(block_on(create_session("ns/s", "", TEMPLATE, &state, false)).unwrap().trim(), "h1");
+        assert_eq!(count(&mock, "scone session create"), 1);
+
+        // unavailable and the session was updated concurrently
+        let mock = mock_runner();
+        mock.respond_once("scone session read ns/s", 0, "name: ns/s\nversion: \"0.2\"\n", "");
+        mock.respond_once("scone session verify", 0, "h0\n", "");
+        mock.respond("scone session read ns/s", 0, "name: ns/s\nversion: \"0.4\"\npredecessor: h0\n", "");
+        mock.respond("scone session verify", 0, "h1\n", "");
+        mock.respond("scone session create", 1, "", UNAVAILABLE);
+        assert!(matches!(block_on(create_session("ns/s", "h0", TEMPLATE, &state, true)), Err(Error::PredecessorMismatch { .. })));
+        assert_eq!(count(&mock, "scone session create"), 1);
+
+        // CAS rejects the predecessor
+        let mock = mock_runner();
+        mock.respond("scone session read ns/s", 0, "name: ns/s\nversion: \"0.2\"\n", "");
+        mock.respond("scone session verify", 0, "h0\n", "");
+        mock.respond("scone session create", 1, "", CONFLICT);
+        assert!(matches!(block_on(create_session("ns/s", "h0", TEMPLATE, &state, true)), Err(Error::PredecessorMismatch { .. })));
+    }
+
+    #[test]
+    fn applying_sessions() {
+        let _guard = RUNNER.lock().unwrap_or_else(|e| e.into_inner());
+        let state = json!({ "session": "ns/s1", "session2": "ns/s2" });
+        // the fingerprint of ns/s1 changed, ns/s2 is not known yet
+        let specs = [SessionSpec { fingerprint: "f0".to_string(), ..SessionSpec::new("ns/s1", "h0", TEMPLATE) }, SessionSpec::new("ns/s2", "", TEMPLATE2)];
+        let respond = |mock: &MockRunner| {
+            mock.respond("scone session read ns/s1", 0, "name: ns/s1\nversion: \"0.2\"\n", "");
+            mock.respond("scone session verify", 0, "h0\n", "");
+            mock.respond_once("scone session read ns/s2", 1, "", UNAVAILABLE);
+            mock.respond("scone session read ns/s2", 1, "", NOT_FOUND);
+            mock.respond_once("scone session create", 0, "h1\n", "");
+        };
+
+        // ns/s1 is updated, ns/s2 is created after CAS became available again
+        let mock = mock_runner();
+        respond(&mock);
+        mock.respond("scone session create", 0, "h2\n", "");
+        let applied = block_on(apply_sessions(&specs, &state, false)).unwrap();
+        assert_eq!(applied.iter().map(|a| (a.session.as_str(), a.hash.as_str(), a.action)).collect::<Vec<_>>(),
+            [("ns/s1", "h1", SessionAction::Update), ("ns/s2", "h2", SessionAction::Create)]);
+        assert_eq!(count(&mock, "scone session read ns/s2 "), 2);
+
+        // ns/s2 was created concurrently: ns/s1 is rolled back
+        let mock = mock_runner();
+        respond(&mock);
+        mock.respond_once("scone session create", 1, "", CONFLICT);
+        mock.respond("scone session create", 0, "h3\n", "");
+        match block_on(apply_sessions(&specs, &state, false)) {
+            Err(Error::Apply { session, rolled_back, source }) => {
+                assert_eq!((session.as_str(), rolled_back), ("ns/s2", vec!["ns/s1".to_string()]));
+                assert!(matches!(*source, Error::PredecessorMismatch { .. }));
+            },
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(count(&mock, "scone session create"), 3);
+    }
+
+    #[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+    struct TestState {
+        secret: String,
+    }
+
+    impl Init for TestState {
+        fn new() -> Self {
+            TestState::default()
+        }
+    }
+
+    impl Versioned for TestState {
+        const SCHEMA_VERSION: u32 = 1;
+    }
+
+    #[test]
+    fn state_within_a_runtime() {
+        let file = std::env::temp_dir().join(format!("scone_cli_state_{}.js", std::process::id())).to_string_lossy().to_string();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            write_state(&TestState { secret: "s".to_string() }, &file).await.unwrap();
+            assert_eq!(read_state::<TestState>(&file).await.unwrap(), TestState { secret: "s".to_string() });
+            // the sync API fails instead of nesting runtimes
+            assert!(matches!(crate::read_state::<TestState>(&file), Err(Error::Io { .. })));
+        });
+        crate::unlock_state(&file);
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn mrenclaves_without_digest() {
+        let _guard = RUNNER.lock().unwrap_or_else(|e| e.into_inner());
+        let measurement = |mrenclave: &str, digest: &str| Measurement { mrenclave: mrenclave.to_string(), digest: digest.to_string(), ..Measurement::new("img", "/bin/svc") };
+        let mut state = json!({ "mrenclaves": { "svc": measurement("m0", "") } });
+
+        // the digest cannot be determined, e.g., since the image was not pulled: keep the MRENCLAVE
+        let mock = mock_runner();
+        mock.respond("image_digest img", 1, "", "Error: No such image: img");
+        assert!(block_on(check_mrenclaves(&mut state, "mrenclaves", false)).unwrap().is_empty());
+        assert_eq!(state["mrenclaves"]["svc"], to_json_value(measurement("m0", "")).unwrap());
+        assert_eq!(count(&mock, "run_image"), 0);
+
+        // a state without digests records the digest without measuring again
+        let mock = mock_runner();
+        mock.respond("image_digest img", 0, "sha256:1\n", "");
+        assert!(block_on(check_mrenclaves(&mut state, "mrenclaves", false)).unwrap().is_empty());
+        assert_eq!(state["mrenclaves"]["svc"], to_json_value(measurement("m0", "sha256:1")).unwrap());
+        assert_eq!(count(&mock, "run_image"), 0);
+
+        // the image changed: measure again
+        let mock = mock_runner();
+        mock.respond("image_digest img", 0, "sha256:2\n", "");
+        mock.respond("run_image img /bin/svc", 0, "m1\n", "");
+        assert_eq!(block_on(check_mrenclaves(&mut state, "mrenclaves", false)).unwrap(), ["svc"]);
+        assert_eq!(state["mrenclaves"]["svc"], to_json_value(measurement("m1", "sha256:2")).unwrap());
+    }
+}
@@ -0,0 +1,191 @@
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{render_session, Error, Result, Session, SessionAction};
+
+/// Session managed by `apply_sessions`: its template and what we know about its current version.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionSpec {
+    pub name: String,               // name of the session in CAS, e.g., namespace/session
+    pub hash: String,               // hash of the current version - empty if not yet known
+    pub template: String,           // session template - rendered with the fields of the state
+    pub depends_on: Vec<String>,    // dependencies in addition to the derived ones (see `session_dependencies`)
+    pub fingerprint: String,        // fingerprint of the current version as returned by `apply_sessions` - empty if unknown
+    pub force: bool,                // update the session even if its fingerprint did not change
+}
+
+impl SessionSpec {
+    pub fn new(name: &str, hash: &str, template: &str) -> Self {
+        SessionSpec { name: name.to_string(), hash: hash.trim().to_string(), template: template.to_string(), ..SessionSpec::default() }
+    }
+
+    /// session must be applied after session `name`
+    pub fn with_dependency(mut self, name: &str) -> Self {
+        self.depends_on.push(name.to_string());
+        self
+    }
+
+    /// fingerprint recorded when the session was applied last
+    pub fn with_fingerprint(mut self, fingerprint: &str) -> Self {
+        self.fingerprint = fingerprint.to_string();
+        self
+    }
+
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+}
+
+/// What `apply_sessions` did with a session. Store hash and fingerprint in the state and pass them
+/// to `SessionSpec` the next time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AppliedSession {
+    pub session: String,
+    pub hash: String,               // hash of the current version
+    pub fingerprint: String,        // fingerprint of the inputs the current version was created from
+    pub action: SessionAction,
+}
+
+/// Sessions in the order in which they must be applied and the fingerprint of each session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionOrder {
+    pub order: Vec<String>,
+    pub fingerprints: BTreeMap<String, String>,
+}
+
+/// What `apply_sessions` does with a session - as far as it is decided before CAS is asked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionUpdate {
+    pub name: String,
+    pub fingerprint: String,        // fingerprint of the session rendered from the state (see `session_order`)
+    pub update: bool,               // update the session if it exists - it is created anyway if it does not
+}
+
+/// sessions of `specs` in the order in which `apply_sessions` applies them - and whether it updates them: if `force`
+/// or the `force` flag of the session is set or if its fingerprint changed. `plan_sessions` and `dry_run_sessions` use
+/// the same decision.
+pub fn session_updates<T : Serialize>(specs: &[SessionSpec], state: &T, force: bool) -> Result<Vec<SessionUpdate>> {
+    let SessionOrder { order, fingerprints } = session_order(specs, state)?;
+    Ok(order.into_iter().map(|name| {
+        let spec = spec(specs, &name);
+        let fingerprint = fingerprints[&name].clone();
+        // we adopt the fingerprint of sessions created before we recorded fingerprints
+        let changed = !spec.fingerprint.is_empty() && spec.fingerprint != fingerprint;
+        SessionUpdate { update: force || spec.force || changed, name, fingerprint }
+    }).collect())
+}
+
+/// spec of session `name` - which must be one of `specs`
+pub(crate) fn spec<'a>(specs: &'a [SessionSpec], name: &str) -> &'a SessionSpec {
+    specs.iter().find(|spec| spec.name == name).expect("session of specs")
+}
+
+/// dependencies of each session of `specs`: a session depends on
+///  - the sessions it imports secrets or volumes from,
+///  - the sessions that export secrets or volumes to it,
+///  - the session of its namespace (e.g., `ns` for `ns/session`) and
+///  - the sessions listed in `depends_on`.
+///
+/// Only dependencies on sessions of `specs` are returned.
+pub fn session_dependencies<T : Serialize>(specs: &[SessionSpec], state: &T) -> Result<BTreeMap<String, BTreeSet<String>>> {
+    let sessions = render_all(specs, state)?;
+    dependencies(specs, &sessions)
+}
+
+/// topological order of `specs` - each session after all its dependencies - and the fingerprints of the sessions.
+/// Sessions that do not depend on each other keep their order in `specs`. The fingerprint of a session is the
+/// SHA-256 of its rendered template (without predecessor) and the fingerprints of its dependencies, i.e., it changes
+/// if the session or any session it depends on changes. Fails with `Error::Dependency` on cyclic dependencies.
+pub fn session_order<T : Serialize>(specs: &[SessionSpec], state: &T) -> Result<SessionOrder> {
+    let sessions = render_all(specs, state)?;
+    let dependencies = dependencies(specs, &sessions)?;
+
+    let mut order : Vec<String> = vec![];
+    let mut pending : Vec<&SessionSpec> = specs.iter().collect();
+    while !pending.is_empty() {
+        let ready = pending.iter().position(|spec| dependencies[&spec.name].iter().all(|d| order.contains(d)));
+        match ready {
+            Some(i) => order.push(pending.remove(i).name.clone()),
+            None => {
+                let cycle = cycle(&dependencies, &order, &pending[0].name);
+                return Err(Error::Dependency { session: cycle[0].clone(), message: format!("cyclic dependency {}", cycle.join(" -> ")) })
+            },
+        }
+    }
+
+    let mut fingerprints = BTreeMap::new();
+    for name in &order {
+        let (_, yaml) = sessions.iter().find(|(n, _)| n == name).expect("rendered session");
+        let mut hasher = Sha256::new();
+        hasher.update(yaml.as_bytes());
+        for dependency in &dependencies[name] {
+            hasher.update(format!("\n# {} {}", dependency, fingerprints[dependency]).as_bytes());
+        }
+        fingerprints.insert(name.clone(), HEXLOWER.encode(&hasher.finalize()));
+    }
+    Ok(SessionOrder { order, fingerprints })
+}
+
+/// cycle reached from session `start` - via dependencies that are not yet in `order`
+fn cycle(dependencies: &BTreeMap<String, BTreeSet<String>>, order: &[String], start: &str) -> Vec<String> {
+    let mut path = vec![start.to_string()];
+    loop {
+        let last = path.last().expect("non-empty path");
+        let next = dependencies[last].iter().find(|d| !order.contains(d)).expect("unresolved dependency").clone();
+        if let Some(i) = path.iter().position(|p| *p == next) {
+            path.push(next);
+            return path.split_off(i)
+        }
+        path.push(next);
+    }
+}
+
+/// render all templates without predecessor - the rendered sessions do not depend on the current versions in CAS
+fn render_all<T : Serialize>(specs: &[SessionSpec], state: &T) -> Result<Vec<(String, String)>> {
+    let mut sessions = vec![];
+    for spec in specs {
+        if sessions.iter().any(|(name, _)| name == &spec.name) {
+            return Err(Error::Dependency { session: spec.name.clone(), message: "session is defined more than once".to_string() })
+        }
+        sessions.push((spec.name.clone(), render_session(&spec.name, &spec.template, state, None)?));
+    }
+    Ok(sessions)
+}
+
+fn dependencies(specs: &[SessionSpec], sessions: &[(String, String)]) -> Result<BTreeMap<String, BTreeSet<String>>> {
+    let names : BTreeSet<&String> = specs.iter().map(|spec| &spec.name).collect();
+    let mut dependencies : BTreeMap<String, BTreeSet<String>> = specs.iter().map(|spec| (spec.name.clone(), BTreeSet::new())).collect();
+    for (spec, (_, yaml)) in specs.iter().zip(sessions) {
+        let session = Session::from_yaml(yaml)?;
+        for name in &spec.depends_on {
+            if !names.contains(name) {
+                return Err(Error::Dependency { session: spec.name.clone(), message: format!("depends on unknown session '{}'", name) })
+            }
+            add(&mut dependencies, &spec.name, name);
+        }
+        let imports = session.secrets.iter().filter_map(|s| s.import.as_ref().map(|i| &i.session))
+            .chain(session.volumes.iter().filter_map(|v| v.import.as_ref().map(|i| &i.session)));
+        for exporter in imports.filter(|name| names.contains(name)) {
+            add(&mut dependencies, &spec.name, exporter);
+        }
+        let exports = session.secrets.iter().flat_map(|s| &s.export)
+            .chain(session.volumes.iter().flat_map(|v| &v.export));
+        for importer in exports.map(|e| &e.session).filter(|name| names.contains(name)) {
+            add(&mut dependencies, importer, &spec.name);
+        }
+        for namespace in names.iter().filter(|name| spec.name.starts_with(&format!("{}/", name))) {
+            add(&mut dependencies, &spec.name, namespace);
+        }
+    }
+    Ok(dependencies)
+}
+
+/// session `session` depends on session `on`
+fn add(dependencies: &mut BTreeMap<String, BTreeSet<String>>, session: &str, on: &str) {
+    if session != on {
+        dependencies.entry(session.to_string()).or_default().insert(on.to_string());
+    }
+}
@@ -0,0 +1,261 @@
+use serde::Serialize;
+use serde_yaml::Value;
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+use crate::{orchestrate, read_session, render_session, session_updates, to_json_value, Error, Result, Session, SessionSpec};
+
+/// value shown instead of secret values
+const MASK: &str = "***";
+
+/// state values shorter than this are masked only where they are the complete value - they occur in too many
+/// unrelated values, e.g., a volume version `1`
+const MIN_INJECTED_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// Change of a single field of a session, e.g., `services.sign.environment.SCONE_HEAP`.
+/// Values of secrets and values injected from the state are masked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub path: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ChangeKind::Added => write!(f, "+ {}", self.path)?,
+            ChangeKind::Removed => write!(f, "- {}", self.path)?,
+            ChangeKind::Changed => write!(f, "~ {}", self.path)?,
+        }
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, ": {} -> {}", old, new),
+            (None, Some(v)) | (Some(v), None) => write!(f, ": {}", v),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// What `create_session` would do with a session: create it, update it or leave it unchanged.
+#[derive(Debug, Clone)]
+pub struct SessionPlan {
+    pub name: String,
+    pub hash: Option<String>,       // hash of the current version - None if the session does not exist yet
+    pub update: bool,               // true if the session would be created or updated
+    pub yaml: String,               // rendered session description
+    pub changes: Vec<Change>,       // changes compared to the current version
+}
+
+impl fmt::Display for SessionPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.hash, self.update) {
+            (None, _) => write!(f, "Session {} will be created.", self.name)?,
+            (Some(_), false) => return write!(f, "Session {} will not be updated.", self.name),
+            (Some(_), true) if self.changes.is_empty() => return write!(f, "Session {} will be updated without changes.", self.name),
+            (Some(hash), true) => write!(f, "Session {} (hash {}) will be updated:", self.name, hash.trim())?,
+        }
+        self.changes.iter().try_for_each(|change| write!(f, "\n    {}", change))
+    }
+}
+
+/// Determine what `create_session` with the same arguments would change - without changing anything.
+pub fn plan_session<T : Serialize>(name: &str, hash: &str, template: &str, state: &T, force: bool) -> Result<SessionPlan> {
+    let current = if hash.is_empty() || force { read_session(name)? } else { None };
+    let predecessor = current.as_ref().map(|(_, hash)| hash.as_str());
+    let yaml = render_session(name, template, state, predecessor)?;
+    let new = Session::from_yaml(&yaml)?;
+    let injected = state_values(state)?;
+    let (changes, update) = match &current {
+        Some((current, _)) => (diff_sessions(&Session::from_yaml(current)?, &new, &injected)?, force),
+        None => {
+            let mut changes = vec![];
+            diff_values("", &Value::Mapping(Default::default()), &session_value(&new)?, &injected, &mut changes);
+            (changes, hash.is_empty() || force)
+        },
+    };
+    let hash = if hash.is_empty() || force { current.map(|(_, hash)| hash) } else { Some(hash.to_string()) };
+    Ok(SessionPlan { name: name.to_string(), hash, update, yaml, changes })
+}
+
+/// Determine what `apply_sessions` with the same arguments would change - without changing anything.
+/// The plans are in the order in which the sessions would be applied.
+pub fn plan_sessions<T : Serialize>(specs: &[SessionSpec], state: &T, force: bool) -> Result<Vec<SessionPlan>> {
+    session_updates(specs, state, force)?.into_iter().map(|update| {
+        let spec = orchestrate::spec(specs, &update.name);
+        plan_session(&spec.name, &spec.hash, &spec.template, state, update.update)
+    }).collect()
+}
+
+/// semantic difference between two versions of a session: lists of named entries (services, secrets, ...)
+/// are compared by name. All values under `secrets` and all values containing one of `injected` (e.g., the values
+/// of the state the session was rendered from, see `state_values`) are masked. The predecessor is ignored.
+pub fn diff_sessions(old: &Session, new: &Session, injected: &[String]) -> Result<Vec<Change>> {
+    let mut changes = vec![];
+    diff_values("", &session_value(old)?, &session_value(new)?, injected, &mut changes);
+    Ok(changes)
+}
+
+/// all scalar values of `state` - the values a template can inject into a session
+pub fn state_values<T : Serialize>(state: &T) -> Result<Vec<String>> {
+    fn collect(value: &serde_json::Value, values: &mut Vec<String>) {
+        match value {
+            serde_json::Value::String(s) if !s.is_empty() => values.push(s.clone()),
+            serde_json::Value::Number(n) => values.push(n.to_string()),
+            serde_json::Value::Array(a) => a.iter().for_each(|v| collect(v, values)),
+            serde_json::Value::Object(o) => o.values().for_each(|v| collect(v, values)),
+            _ => {},
+        }
+    }
+    let mut values = vec![];
+    collect(&to_json_value(state)?, &mut values);
+    Ok(values)
+}
+
+fn session_value(session: &Session) -> Result<Value> {
+    let mut value = serde_yaml::to_value(session).map_err(|source| Error::Yaml { context: format!("session {}", session.name), source })?;
+    if let Some(m) = value.as_mapping_mut() {
+        m.remove("predecessor");
+    }
+    Ok(value)
+}
+
+fn diff_values(path: &str, old: &Value, new: &Value, injected: &[String], changes: &mut Vec<Change>) {
+    if old == new {
+        return;
+    }
+    let join = |key: &str| if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) };
+    match (old, new) {
+        (Value::Mapping(old), Value::Mapping(new)) => {
+            for (key, value) in old {
+                let key = key_string(key);
+                match (new.get(key.as_str()), empty_like(value)) {
+                    (Some(new_value), _) => diff_values(&join(&key), value, new_value, injected, changes),
+                    (None, Some(empty)) => diff_values(&join(&key), value, &empty, injected, changes),
+                    (None, None) => changes.push(change(ChangeKind::Removed, &join(&key), Some(value), None, injected)),
+                }
+            }
+            for (key, value) in new {
+                let key = key_string(key);
+                if !old.contains_key(key.as_str()) {
+                    match empty_like(value) {
+                        Some(empty) => diff_values(&join(&key), &empty, value, injected, changes),
+                        None => changes.push(change(ChangeKind::Added, &join(&key), None, Some(value), injected)),
+                    }
+                }
+            }
+        },
+        (Value::Sequence(old), Value::Sequence(new)) if is_named(old) && is_named(new) => {
+            for entry in old {
+                let name = entry_name(entry);
+                match new.iter().find(|e| entry_name(e) == name) {
+                    Some(new_entry) => diff_values(&join(&name), entry, new_entry, injected, changes),
+                    None => changes.push(change(ChangeKind::Removed, &join(&name), None, None, injected)),
+                }
+            }
+            for entry in new {
+                let name = entry_name(entry);
+                if !old.iter().any(|e| entry_name(e) == name) {
+                    changes.push(change(ChangeKind::Added, &join(&name), None, None, injected));
+                }
+            }
+        },
+        _ => changes.push(change(ChangeKind::Changed, path, Some(old), Some(new), injected)),
+    }
+}
+
+fn change(kind: ChangeKind, path: &str, old: Option<&Value>, new: Option<&Value>, injected: &[String]) -> Change {
+    let show = |v: &Value| {
+        let v = value_string(v);
+        if is_secret(path) || is_injected(&v, injected) { MASK.to_string() } else { v }
+    };
+    Change { kind, path: path.to_string(), old: old.map(show), new: new.map(show) }
+}
+
+/// nothing under `secrets` and no OTP shared secret is ever shown - whether the secret is named or not
+fn is_secret(path: &str) -> bool {
+    path == "secrets" || path.starts_with("secrets.") || path.ends_with("one_time_password_shared_secret")
+}
+
+/// true if `value` is or contains a value injected from the state
+fn is_injected(value: &str, injected: &[String]) -> bool {
+    injected.iter().any(|i| value == i || (i.len() >= MIN_INJECTED_LEN && value.contains(i.as_str())))
+}
+
+/// lists of named entries, like services and secrets
+fn is_named(entries: &[Value]) -> bool {
+    entries.iter().all(|e| e.get("name").and_then(Value::as_str).is_some())
+}
+
+/// empty mapping or list of named entries: added and removed containers are compared against these
+/// to list their entries - without showing their (maybe secret) values.
+fn empty_like(value: &Value) -> Option<Value> {
+    match value {
+        Value::Mapping(_) => Some(Value::Mapping(Default::default())),
+        Value::Sequence(entries) if is_named(entries) => Some(Value::Sequence(vec![])),
+        _ => None,
+    }
+}
+
+fn entry_name(entry: &Value) -> String {
+    entry.get("name").and_then(Value::as_str).unwrap_or_default().to_string()
+}
+
+fn key_string(key: &Value) -> String {
+    key.as_str().map(str::to_string).unwrap_or_else(|| value_string(key))
+}
+
+fn value_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        _ => serde_yaml::to_string(value).map(|s| s.trim().replace('\n', " ")).unwrap_or_default(),
+    }
+}
+
+/// ask the user to confirm: returns true if the answer is `y` or `yes`
+pub fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    let stdio_error = |source| Error::Io { path: "stdin".to_string(), source };
+    io::stdout().flush().map_err(stdio_error)?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).map_err(stdio_error)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(old: &str, new: &str, injected: &[&str]) -> Vec<String> {
+        let injected : Vec<String> = injected.iter().map(|s| s.to_string()).collect();
+        let session = |yaml: &str| Session::from_yaml(&format!("name: s\nversion: \"0.3\"\n{}", yaml)).unwrap();
+        diff_sessions(&session(old), &session(new), &injected).unwrap().iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn secrets_are_masked() {
+        assert_eq!(diff("secrets:\n- name: a\n  kind: ascii\n  value: old\n", "secrets:\n- name: a\n  kind: binary\n  value: new\n", &[]),
+            vec!["~ secrets.a.kind: *** -> ***", "~ secrets.a.value: *** -> ***"]);
+        // lists without names are compared as a whole
+        assert_eq!(diff("secrets:\n- name: a\n  export:\n  - session: old\n", "secrets:\n- name: a\n  export:\n  - session: new\n", &[]),
+            vec!["~ secrets.a.export: *** -> ***"]);
+    }
+
+    #[test]
+    fn injected_values_are_masked() {
+        let old = "services:\n- name: otp\n  command: otp --secret JBSWY3DPEHPK3PXP\n  pwd: /old\n";
+        let new = "services:\n- name: otp\n  command: otp --secret KRSXG5CTMVRXEZLU\n  pwd: /new\n";
+        assert_eq!(diff(old, new, &["JBSWY3DPEHPK3PXP", "KRSXG5CTMVRXEZLU", "/new"]),
+            vec!["~ services.otp.command: *** -> ***", "~ services.otp.pwd: /old -> ***"]);
+        assert_eq!(diff(old, new, &[]).len(), 2);
+        assert_eq!(diff(old, new, &[])[1], "~ services.otp.pwd: /old -> /new");
+    }
+}
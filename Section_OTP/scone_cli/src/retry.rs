@@ -0,0 +1,82 @@
+use log::warn;
+use std::env;
+use std::future::Future;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::{CommandOutput, FailureKind};
+
+/// How often and how long to retry `scone` commands that failed with a transient failure
+/// (`FailureKind::Unavailable`), e.g., since CAS is busy. The delay between two attempts
+/// grows exponentially from `initial_delay` up to `max_delay`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,          // attempts including the first one - 1 disables retries
+    pub initial_delay: Duration,    // delay before the first retry
+    pub max_delay: Duration,        // upper bound of the delay between two attempts
+    pub multiplier: f64,            // factor by which the delay grows after each retry
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// do not retry at all
+    pub fn none() -> Self {
+        RetryPolicy { max_attempts: 1, ..RetryPolicy::default() }
+    }
+
+    /// delay before retry number `retry` (starting with 1)
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.max(1.0).powi(retry.saturating_sub(1).min(i32::MAX as u32) as i32);
+        // the factor overflows `Duration` after enough retries
+        Duration::try_from_secs_f64(self.initial_delay.as_secs_f64() * factor).unwrap_or(self.max_delay).min(self.max_delay)
+    }
+}
+
+static RETRY_POLICY: RwLock<Option<RetryPolicy>> = RwLock::new(None);
+
+/// replace the retry policy used by all functions of this crate
+pub fn set_retry_policy(policy: RetryPolicy) {
+    *RETRY_POLICY.write().unwrap_or_else(|e| e.into_inner()) = Some(policy);
+}
+
+/// the retry policy used by all functions of this crate. Unless set via `set_retry_policy`, this is
+/// `RetryPolicy::default()` with the number of retries taken from environment variable `SCONE_CLI_RETRIES` (if set).
+pub fn retry_policy() -> RetryPolicy {
+    if let Some(policy) = RETRY_POLICY.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        return policy.clone()
+    }
+    match env::var("SCONE_CLI_RETRIES").ok().and_then(|n| n.parse::<u32>().ok()) {
+        Some(retries) => RetryPolicy { max_attempts: retries.saturating_add(1), ..RetryPolicy::default() },
+        None => RetryPolicy::default(),
+    }
+}
+
+/// execute `command` until it does not fail with a transient failure or the attempts of the retry policy are used up
+pub(crate) async fn retry<F, Fut>(what: &str, mut command: F) -> CommandOutput
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = CommandOutput>,
+{
+    let policy = retry_policy();
+    let mut attempt = 1;
+    loop {
+        let output = command().await;
+        if output.code == 0 || output.failure_kind() != FailureKind::Unavailable || attempt >= policy.max_attempts {
+            return output
+        }
+        let delay = policy.delay(attempt);
+        warn!("{} failed (attempt {} of {}): {} - retrying in {:?}", what, attempt, policy.max_attempts, output.stderr.trim(), delay);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
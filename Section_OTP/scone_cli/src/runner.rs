@@ -0,0 +1,303 @@
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// image that contains the SCONE CLI
+pub const SCONE_CLI_IMAGE: &str = "registry.scontain.com:5050/sconecuratedimages/sconecli";
+
+/// Executes SCONE CLI commands.
+///
+/// `run` gets a complete command line like `scone session read NAME > FILE` and returns
+/// the exit code, stdout and stderr. `run_image` runs a command in a given container image,
+/// e.g., to determine the MRENCLAVE of a binary.
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, cmd: &str) -> Output;
+    fn run_image(&self, image: &str, args: &str, env: &[(&str, &str)]) -> Output;
+
+    /// async variant of `run` - by default, `run` is executed on the current thread
+    fn run_async<'a>(&'a self, cmd: &'a str) -> BoxFuture<'a, Output> {
+        Box::pin(async move { self.run(cmd) })
+    }
+
+    /// async variant of `run_image` - by default, `run_image` is executed on the current thread
+    fn run_image_async<'a>(&'a self, image: &'a str, args: &'a str, env: &'a [(&'a str, &'a str)]) -> BoxFuture<'a, Output> {
+        Box::pin(async move { self.run_image(image, args, env) })
+    }
+
+    /// digest (image ID) of the local copy of `image` - changes whenever the image is rebuilt or pulled.
+    /// By default, the image is inspected with `docker`.
+    fn image_digest(&self, image: &str) -> Output {
+        execute("sh", &inspect_command("docker", image))
+    }
+
+    /// async variant of `image_digest` - by default, `image_digest` is executed on the current thread
+    fn image_digest_async<'a>(&'a self, image: &'a str) -> BoxFuture<'a, Output> {
+        Box::pin(async move { self.image_digest(image) })
+    }
+}
+
+/// future returned by the async methods of `CommandRunner`
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// exit code, stdout and stderr of a command
+type Output = (i32, String, String);
+
+/// execute the given command line using `shell`
+pub fn execute(shell: &str, cmd: &str) -> Output {
+    let mut command = ::std::process::Command::new(shell);
+    command.arg("-c").arg(cmd);
+    match command.output() {
+        Ok(output) => {
+            (output.status.code().unwrap_or(if output.status.success() { 0 } else { 1 }),
+             String::from_utf8_lossy(&output.stdout[..]).into_owned(),
+             String::from_utf8_lossy(&output.stderr[..]).into_owned())
+        },
+        Err(e) => (126, String::new(), e.to_string()),
+    }
+}
+
+/// async variant of `execute`: the command line is executed by a tokio child process
+pub async fn execute_async(shell: &str, cmd: &str) -> Output {
+    let mut command = tokio::process::Command::new(shell);
+    command.arg("-c").arg(cmd);
+    match command.output().await {
+        Ok(output) => {
+            (output.status.code().unwrap_or(if output.status.success() { 0 } else { 1 }),
+             String::from_utf8_lossy(&output.stdout[..]).into_owned(),
+             String::from_utf8_lossy(&output.stderr[..]).into_owned())
+        },
+        Err(e) => (126, String::new(), e.to_string()),
+    }
+}
+
+/// `s` quoted for the shell: single quotes are kept literally as `'\''`
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn image_command(engine: &str, image: &str, args: &str, env: &[(&str, &str)]) -> String {
+    let env : String = env.iter().map(|(k, v)| format!("-e {} ", shell_quote(&format!("{}={}", k, v)))).collect();
+    format!("{} run --rm {}{} {}", engine, env, image, args)
+}
+
+fn inspect_command(engine: &str, image: &str) -> String {
+    format!("{} image inspect --format '{{{{.Id}}}}' {}", engine, image)
+}
+
+/// Runs the SCONE CLI inside a container - using `docker` or `podman`.
+#[derive(Debug, Clone)]
+pub struct ContainerRunner {
+    pub engine: String,     // docker or podman
+    pub socket: String,     // socket of the engine - mounted as docker socket into the CLI container
+    pub image: String,      // image containing the SCONE CLI
+    pub shell: String,      // shell used to execute the command line
+}
+
+impl ContainerRunner {
+    pub fn docker() -> Self {
+        ContainerRunner {
+            engine: "docker".to_string(),
+            socket: "/var/run/docker.sock".to_string(),
+            image: SCONE_CLI_IMAGE.to_string(),
+            shell: "sh".to_string(),
+        }
+    }
+
+    pub fn podman() -> Self {
+        ContainerRunner {
+            engine: "podman".to_string(),
+            socket: "${XDG_RUNTIME_DIR:-/run}/podman/podman.sock".to_string(),
+            ..ContainerRunner::docker()
+        }
+    }
+
+    /// command line that executes `cmd` in the SCONE CLI container
+    fn command(&self, cmd: &str) -> String {
+        format!(r#"{} run --rm -v "{}:/var/run/docker.sock" -v "$HOME/.docker:/root/.docker" -v "$HOME/.cas:/root/.cas" -v "$HOME/.scone:/root/.scone" -v "$PWD:/root"     -w /root     {} {}"#, self.engine, self.socket, self.image, cmd)
+    }
+}
+
+impl CommandRunner for ContainerRunner {
+    fn run(&self, cmd: &str) -> Output {
+        execute(&self.shell, &self.command(cmd))
+    }
+
+    fn run_image(&self, image: &str, args: &str, env: &[(&str, &str)]) -> Output {
+        execute(&self.shell, &image_command(&self.engine, image, args, env))
+    }
+
+    fn run_async<'a>(&'a self, cmd: &'a str) -> BoxFuture<'a, Output> {
+        Box::pin(async move { execute_async(&self.shell, &self.command(cmd)).await })
+    }
+
+    fn run_image_async<'a>(&'a self, image: &'a str, args: &'a str, env: &'a [(&'a str, &'a str)]) -> BoxFuture<'a, Output> {
+        Box::pin(async move { execute_async(&self.shell, &image_command(&self.engine, image, args, env)).await })
+    }
+
+    fn image_digest(&self, image: &str) -> Output {
+        execute(&self.shell, &inspect_command(&self.engine, image))
+    }
+
+    fn image_digest_async<'a>(&'a self, image: &'a str) -> BoxFuture<'a, Output> {
+        Box::pin(async move { execute_async(&self.shell, &inspect_command(&self.engine, image)).await })
+    }
+}
+
+/// Runs a locally installed `scone` binary. Container images are run with `engine`.
+#[derive(Debug, Clone)]
+pub struct LocalRunner {
+    pub engine: String,
+    pub shell: String,
+}
+
+impl Default for LocalRunner {
+    fn default() -> Self {
+        LocalRunner { engine: "docker".to_string(), shell: "sh".to_string() }
+    }
+}
+
+impl CommandRunner for LocalRunner {
+    fn run(&self, cmd: &str) -> Output {
+        execute(&self.shell, cmd)
+    }
+
+    fn run_image(&self, image: &str, args: &str, env: &[(&str, &str)]) -> Output {
+        execute(&self.shell, &image_command(&self.engine, image, args, env))
+    }
+
+    fn run_async<'a>(&'a self, cmd: &'a str) -> BoxFuture<'a, Output> {
+        Box::pin(execute_async(&self.shell, cmd))
+    }
+
+    fn run_image_async<'a>(&'a self, image: &'a str, args: &'a str, env: &'a [(&'a str, &'a str)]) -> BoxFuture<'a, Output> {
+        Box::pin(async move { execute_async(&self.shell, &image_command(&self.engine, image, args, env)).await })
+    }
+
+    fn image_digest(&self, image: &str) -> Output {
+        execute(&self.shell, &inspect_command(&self.engine, image))
+    }
+
+    fn image_digest_async<'a>(&'a self, image: &'a str) -> BoxFuture<'a, Output> {
+        Box::pin(async move { execute_async(&self.shell, &inspect_command(&self.engine, image)).await })
+    }
+}
+
+/// Records all commands and answers with canned responses - no command is executed.
+///
+/// A response is selected by the longest registered prefix of the command line - responses
+/// registered with `respond_once` take precedence and are used in the order of registration.
+/// Commands without a matching prefix succeed with empty output. Like a shell, the stdout of a
+/// command line ending with `> FILE` is written to FILE. Container images are recorded as
+/// `run_image IMAGE ARGS` and digest lookups as `image_digest IMAGE`.
+#[derive(Debug, Default)]
+pub struct MockRunner {
+    responses: Mutex<Vec<(String, Output)>>,
+    once: Mutex<Vec<(String, Output)>>,
+    commands: Mutex<Vec<String>>,
+}
+
+impl MockRunner {
+    pub fn new() -> Self {
+        MockRunner::default()
+    }
+
+    /// respond to commands starting with `prefix` with the given exit code, stdout and stderr
+    pub fn respond(&self, prefix: &str, code: i32, stdout: &str, stderr: &str) {
+        self.responses.lock().unwrap().push((prefix.to_string(), (code, stdout.to_string(), stderr.to_string())));
+    }
+
+    /// respond to the next command starting with `prefix` with the given exit code, stdout and stderr
+    pub fn respond_once(&self, prefix: &str, code: i32, stdout: &str, stderr: &str) {
+        self.once.lock().unwrap().push((prefix.to_string(), (code, stdout.to_string(), stderr.to_string())));
+    }
+
+    /// all commands executed so far
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    fn record(&self, cmd: String) -> Output {
+        let mut once = self.once.lock().unwrap();
+        let response = match once.iter().position(|(prefix, _)| cmd.starts_with(prefix.as_str())) {
+            Some(i) => once.remove(i).1,
+            None => self.responses.lock().unwrap().iter()
+                .filter(|(prefix, _)| cmd.starts_with(prefix.as_str()))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, response)| response.clone())
+                .unwrap_or((0, String::new(), String::new())),
+        };
+        drop(once);
+        let file = cmd.rsplit_once(" > ").map(|(_, file)| file.trim().to_string());
+        self.commands.lock().unwrap().push(cmd);
+        match file {
+            Some(file) => {
+                let (code, stdout, stderr) = response;
+                match std::fs::write(&file, stdout) {
+                    Ok(()) => (code, String::new(), stderr),
+                    Err(e) => (1, String::new(), format!("{}: {}", file, e)),
+                }
+            },
+            None => response,
+        }
+    }
+}
+
+impl CommandRunner for MockRunner {
+    fn run(&self, cmd: &str) -> Output {
+        self.record(cmd.to_string())
+    }
+
+    fn run_image(&self, image: &str, args: &str, _env: &[(&str, &str)]) -> Output {
+        self.record(format!("run_image {} {}", image, args))
+    }
+
+    fn image_digest(&self, image: &str) -> Output {
+        self.record(format!("image_digest {}", image))
+    }
+}
+
+static RUNNER: RwLock<Option<Arc<dyn CommandRunner>>> = RwLock::new(None);
+
+/// runner for a given name: `docker`, `podman` or `local`
+pub fn runner_from_name(name: &str) -> Option<Arc<dyn CommandRunner>> {
+    match name {
+        "docker" => Some(Arc::new(ContainerRunner::docker())),
+        "podman" => Some(Arc::new(ContainerRunner::podman())),
+        "local" => Some(Arc::new(LocalRunner::default())),
+        _ => None,
+    }
+}
+
+/// replace the runner used by `scone!` and all functions of this crate
+pub fn set_runner(runner: Arc<dyn CommandRunner>) {
+    *RUNNER.write().unwrap() = Some(runner);
+}
+
+/// the runner used by `scone!`. Unless set via `set_runner`, it is selected by the
+/// environment variable `SCONE_CLI_RUNNER` and defaults to `docker`.
+pub fn runner() -> Arc<dyn CommandRunner> {
+    if let Some(runner) = RUNNER.read().unwrap().as_ref() {
+        return runner.clone();
+    }
+    let name = env::var("SCONE_CLI_RUNNER").unwrap_or_else(|_| "docker".to_string());
+    let runner = runner_from_name(&name).unwrap_or_else(|| {
+        log::error!("Unknown runner '{}' in SCONE_CLI_RUNNER: using docker.", name);
+        Arc::new(ContainerRunner::docker())
+    });
+    RUNNER.write().unwrap().get_or_insert(runner).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_command_quotes_env() {
+        let value = r#"a"b $HOME `id` 'c'"#;
+        let cmd = image_command("printf '%s\n'", "image", "args", &[("KEY", value)]);
+        let (code, stdout, _) = execute("sh", &cmd);
+        assert_eq!(code, 0);
+        assert_eq!(stdout, format!("run\n--rm\n-e\nKEY={}\nimage\nargs\n", value));
+    }
+}
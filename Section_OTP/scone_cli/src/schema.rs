@@ -0,0 +1,77 @@
+use log::info;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::{Error, Result};
+
+/// field of the state file that contains the version of its schema - state files without this field have version 0
+pub const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// upgrade step of a state from version N to N+1 - operates on the JSON representation of the state
+pub type Migration = fn(&mut Value) -> Result<()>;
+
+/// State objects stored with `write_state` / `read_state` declare the version of their schema. Whenever the
+/// layout of the state changes, increment `SCHEMA_VERSION` and add a migration step from the previous version.
+pub trait Versioned {
+    /// version of the schema written by `write_state`
+    const SCHEMA_VERSION: u32;
+
+    /// upgrade steps of older state files
+    fn migrations() -> Migrations {
+        Migrations::default()
+    }
+}
+
+/// Registry of the migration steps of a state: step `N` upgrades a state from version N to N+1.
+#[derive(Default, Clone)]
+pub struct Migrations {
+    steps: BTreeMap<u32, Migration>,
+}
+
+impl Migrations {
+    pub fn new() -> Self {
+        Migrations::default()
+    }
+
+    /// register migration from version `from` to version `from + 1`
+    pub fn add(mut self, from: u32, migration: Migration) -> Self {
+        self.steps.insert(from, migration);
+        self
+    }
+
+    /// upgrade `state` (of file `path`) step by step to version `to`
+    pub fn migrate(&self, state: &mut Value, path: &str, to: u32) -> Result<()> {
+        let from = schema_version(state);
+        if from > to {
+            return Err(Error::StateMigration { path: path.to_string(), version: from, message: format!("state was written by a newer version of this tool - we support up to version {}", to) })
+        }
+        for version in from..to {
+            let step = self.steps.get(&version).ok_or_else(|| Error::StateMigration { path: path.to_string(), version, message: format!("no migration to version {} defined", version + 1) })?;
+            step(state).map_err(|e| Error::StateMigration { path: path.to_string(), version, message: e.to_string() })?;
+            info!("Migrated state in {} from version {} to {}", path, version, version + 1);
+        }
+        set_schema_version(state, to);
+        Ok(())
+    }
+}
+
+/// version of the schema of `state` - 0 if the state does not contain a version
+pub fn schema_version(state: &Value) -> u32 {
+    state.get(SCHEMA_VERSION_KEY).and_then(Value::as_u64).map(|v| v as u32).unwrap_or(0)
+}
+
+pub(crate) fn set_schema_version(state: &mut Value, version: u32) {
+    if let Some(m) = state.as_object_mut() {
+        m.insert(SCHEMA_VERSION_KEY.to_string(), version.into());
+    }
+}
+
+/// migrate `state` (read from `path`) to the current schema version of `T` and deserialize it
+pub(crate) fn upgrade<T: Versioned + for<'de> Deserialize<'de>>(mut state: Value, path: &str) -> Result<T> {
+    T::migrations().migrate(&mut state, path, T::SCHEMA_VERSION)?;
+    if let Some(m) = state.as_object_mut() {
+        m.remove(SCHEMA_VERSION_KEY);
+    }
+    serde_json::from_value(state).map_err(|source| Error::Json { context: format!("state in '{}'", path), source })
+}
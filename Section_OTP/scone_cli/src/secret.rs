@@ -0,0 +1,119 @@
+use data_encoding::{BASE32_NOPAD, BASE64, HEXLOWER};
+use rand::distributions::{Distribution, Uniform};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::fmt;
+use zeroize::Zeroizing;
+
+use crate::{Error, Result};
+
+/// alphabet of `random_name` and the default alphabet of ascii secrets
+pub const ALPHANUMERIC: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Kind of a generated secret - corresponds to the secret kinds of CAS: `Binary` is a CAS secret of kind `binary`,
+/// all other kinds are CAS secrets of kind `ascii`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretKind {
+    Ascii { size: usize, alphabet: String },    // `size` characters drawn from `alphabet`
+    Binary { size: usize },                     // `size` random bytes
+    Base32 { size: usize },                     // `size` random bytes - base32 encoded without padding
+    Base64 { size: usize },                     // `size` random bytes - base64 encoded
+    Hex { size: usize },                        // `size` random bytes - hex encoded
+}
+
+impl SecretKind {
+    /// `size` alphanumeric characters
+    pub fn alphanumeric(size: usize) -> Self {
+        SecretKind::Ascii { size, alphabet: ALPHANUMERIC.to_string() }
+    }
+
+    /// kind of the secret in a session description: `ascii` or `binary`
+    pub fn cas_kind(&self) -> &'static str {
+        match self {
+            SecretKind::Binary { .. } => "binary",
+            _ => "ascii",
+        }
+    }
+}
+
+/// Generated secret value. The value is overwritten with zeros when the secret is dropped and it is never
+/// printed by `Debug`. Use `expose` or `expose_str` to access the value.
+#[derive(Clone)]
+pub struct Secret {
+    value: Zeroizing<Vec<u8>>,
+}
+
+impl Secret {
+    /// generate a secret of kind `kind` with the CSPRNG of the operating system
+    pub fn generate(kind: &SecretKind) -> Result<Self> {
+        match kind {
+            SecretKind::Ascii { size, alphabet } => Secret::ascii(*size, alphabet),
+            SecretKind::Binary { size } => Ok(Secret::binary(*size)),
+            SecretKind::Base32 { size } => Ok(Secret::binary(*size).encode(|v| BASE32_NOPAD.encode(v))),
+            SecretKind::Base64 { size } => Ok(Secret::binary(*size).encode(|v| BASE64.encode(v))),
+            SecretKind::Hex { size } => Ok(Secret::binary(*size).encode(|v| HEXLOWER.encode(v))),
+        }
+    }
+
+    /// `size` random bytes
+    pub fn binary(size: usize) -> Self {
+        let mut value = Zeroizing::new(vec![0u8; size]);
+        OsRng.fill_bytes(&mut value);
+        Secret { value }
+    }
+
+    /// `size` characters drawn uniformly from the ASCII characters of `alphabet`
+    pub fn ascii(size: usize, alphabet: &str) -> Result<Self> {
+        if alphabet.is_empty() || !alphabet.is_ascii() {
+            return Err(Error::Secret { message: format!("alphabet '{}' is empty or contains non-ASCII characters", alphabet) })
+        }
+        let alphabet = alphabet.as_bytes();
+        let index = Uniform::from(0..alphabet.len());
+        let value = (0..size).map(|_| alphabet[index.sample(&mut OsRng)]).collect();
+        Ok(Secret { value: Zeroizing::new(value) })
+    }
+
+    /// the value of the secret
+    pub fn expose(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// the value of an ascii secret - `None` for binary secrets that are not valid UTF-8
+    pub fn expose_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.value).ok()
+    }
+
+    /// length of the value in bytes
+    pub fn len(&self) -> usize {
+        self.value.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    /// base32 encoding (without padding) of the value, e.g., for OTP secrets
+    pub fn to_base32(&self) -> Zeroizing<String> {
+        Zeroizing::new(BASE32_NOPAD.encode(&self.value))
+    }
+
+    /// base64 encoding of the value
+    pub fn to_base64(&self) -> Zeroizing<String> {
+        Zeroizing::new(BASE64.encode(&self.value))
+    }
+
+    /// hex encoding of the value
+    pub fn to_hex(&self) -> Zeroizing<String> {
+        Zeroizing::new(HEXLOWER.encode(&self.value))
+    }
+
+    fn encode(&self, encode: impl Fn(&[u8]) -> String) -> Secret {
+        Secret { value: Zeroizing::new(encode(&self.value).into_bytes()) }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(<redacted: {} bytes>)", self.value.len())
+    }
+}
@@ -0,0 +1,267 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+
+use crate::{Error, Result};
+
+/// fields of a session description that are not covered by this model - preserved when re-serialising
+pub type Other = BTreeMap<String, Value>;
+
+/// Session description as understood by CAS (session format version 0.3).
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Session {
+    pub name: String,
+    #[serde(deserialize_with = "version")]
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predecessor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_policy: Option<AccessPolicy>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<Service>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security: Option<Security>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<Volume>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<Image>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secrets: Vec<Secret>,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AccessPolicy {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub read: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub update: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub create_sessions: Vec<String>,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Service {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty", deserialize_with = "scalar_map")]
+    pub environment: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attestation: Vec<ServiceAttestation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pwd: Option<String>,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ServiceAttestation {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mrenclave: Vec<String>,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Security {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<SecurityAttestation>,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SecurityAttestation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tolerate: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub one_time_password_shared_secret: Option<String>,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Volume {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub export: Vec<Export>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub import: Option<VolumeImport>,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// export of a volume or secret to another session
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Export {
+    pub session: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_hash: Option<String>,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct VolumeImport {
+    pub session: String,
+    pub volume: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_hash: Option<String>,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Image {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<ImageVolume>,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// volume mounted by an image at `path`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ImageVolume {
+    pub name: String,
+    pub path: String,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Secret {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "scalar")]
+    pub value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub import: Option<SecretImport>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub export: Vec<Export>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub export_public: Option<bool>,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SecretImport {
+    pub session: String,
+    pub secret: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_hash: Option<String>,
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+impl AccessPolicy {
+    /// only the creator of the session can read and update the session and create sessions in its namespace
+    pub fn creator() -> Self {
+        AccessPolicy {
+            read: vec!["CREATOR".to_string()],
+            update: vec!["CREATOR".to_string()],
+            create_sessions: vec!["CREATOR".to_string()],
+            other: Other::new(),
+        }
+    }
+}
+
+impl Session {
+    /// empty session `name` (format version 0.3) that only the creator can access
+    pub fn new(name: &str) -> Self {
+        Session {
+            name: name.to_string(),
+            version: "0.3".to_string(),
+            access_policy: Some(AccessPolicy::creator()),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_yaml(yaml: &str) -> Result<Self> {
+        serde_yaml::from_str(yaml).map_err(|source| Error::Yaml { context: "session".to_string(), source })
+    }
+
+    pub fn to_yaml(&self) -> Result<String> {
+        serde_yaml::to_string(self).map_err(|source| Error::Yaml { context: format!("session {}", self.name), source })
+    }
+
+    pub fn service(&self, name: &str) -> Option<&Service> {
+        self.services.iter().find(|s| s.name == name)
+    }
+
+    pub fn service_mut(&mut self, name: &str) -> Option<&mut Service> {
+        self.services.iter_mut().find(|s| s.name == name)
+    }
+
+    pub fn secret(&self, name: &str) -> Option<&Secret> {
+        self.secrets.iter().find(|s| s.name == name)
+    }
+
+    pub fn secret_mut(&mut self, name: &str) -> Option<&mut Secret> {
+        self.secrets.iter_mut().find(|s| s.name == name)
+    }
+
+    pub fn volume(&self, name: &str) -> Option<&Volume> {
+        self.volumes.iter().find(|v| v.name == name)
+    }
+
+    pub fn image(&self, name: &str) -> Option<&Image> {
+        self.images.iter().find(|i| i.name == name)
+    }
+}
+
+/// scalars as strings, e.g., `0.3` as "0.3" - `None` for sequences and mappings
+pub(crate) fn scalar_to_string(value: Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// environment variables might be given as numbers or booleans, e.g., `SCONE_HEAP: 1`
+fn scalar_map<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<BTreeMap<String, String>, D::Error> {
+    let map : BTreeMap<String, Value> = BTreeMap::deserialize(deserializer)?;
+    map.into_iter()
+        .map(|(k, v)| scalar_to_string(v).map(|v| (k.clone(), v)).ok_or_else(|| serde::de::Error::custom(format!("value of '{}' is not a scalar", k))))
+        .collect()
+}
+
+fn scalar<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<String>, D::Error> {
+    let value : Option<Value> = Option::deserialize(deserializer)?;
+    match value {
+        None | Some(Value::Null) => Ok(None),
+        Some(v) => scalar_to_string(v).map(Some).ok_or_else(|| serde::de::Error::custom("value is not a scalar")),
+    }
+}
+
+/// the session version might be given as number, i.e., `version: 0.3` instead of `version: "0.3"`
+fn version<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<String, D::Error> {
+    scalar_to_string(Value::deserialize(deserializer)?).ok_or_else(|| serde::de::Error::custom("version is not a scalar"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_is_number_or_string() {
+        for yaml in ["name: s\nversion: 0.3\n", "name: s\nversion: \"0.3\"\n", "name: s\nversion: '0.3'\n"] {
+            assert_eq!(Session::from_yaml(yaml).unwrap().version, "0.3", "{}", yaml);
+        }
+        assert!(Session::from_yaml("name: s\nversion: [0.3]\n").is_err());
+    }
+}
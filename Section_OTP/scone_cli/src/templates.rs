@@ -0,0 +1,460 @@
+//! Library of Handlebars partials and helpers available in all session templates.
+//!
+//! Partials are included with `{{> name param=value}}`. A partial on a line of its own is indented like the line,
+//! i.e., it can be used at any nesting level of the YAML document:
+//!
+//! - `session_header name=NAME`: name, version and predecessor of the session
+//! - `creator_access_policy`: access policy that grants all permissions to the creator only
+//! - `namespace`: complete session of namespace `{{namespace}}`
+//! - `attestation mrenclave=MRENCLAVE enabled=BOOL`: attestation block of a service - commented out unless `enabled`
+//! - `otpqr_service command=COMMAND reset=BOOL`: service `otpqr` that generates the QR code of the OTP secret
+//! - `otpqr_image`: image `otpqr_image` with the single_run volume mounted at `/root/single_run`
+//! - `single_run_export to=SESSION` / `single_run_import from=SESSION`: single_run volume exported to / imported from SESSION
+//!
+//! Helpers: `yaml-quote` (double-quoted YAML string), `base64` and `sha256` (hex) of a string.
+//!
+//! `check_template` determines - without rendering - which variables a template refers to that are not defined
+//! and which parameters it defines but never refers to.
+
+use data_encoding::{BASE64, HEXLOWER};
+use handlebars::template::{BlockParam, DecoratorTemplate, HelperTemplate, Parameter, Template, TemplateElement};
+use handlebars::{handlebars_helper, no_escape, Handlebars};
+use log::info;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::RwLock;
+
+use crate::{Error, Result};
+
+const SESSION_HEADER: &str = r#"name: {{name}}
+version: "0.3"
+{{predecessor_key}}: {{predecessor}}
+"#;
+
+const CREATOR_ACCESS_POLICY: &str = r#"access_policy:
+  read:
+    - CREATOR
+  update:
+    - CREATOR
+  create_sessions:
+    - CREATOR
+"#;
+
+const NAMESPACE: &str = r#"{{> session_header name=namespace}}
+
+{{> creator_access_policy}}
+"#;
+
+const ATTESTATION: &str = r#"{{#if enabled}}
+attestation:
+  - mrenclave:
+    - {{mrenclave}}
+{{else}}
+## enable for release mode:
+# attestation:
+#  - mrenclave:
+#    - {{mrenclave}}
+{{/if}}
+"#;
+
+const OTPQR_SERVICE: &str = r#"- name: otpqr
+  image_name: otpqr_image
+{{#if command}}
+  command: {{command}}
+{{/if}}
+  {{> attestation mrenclave=mrenclaves.otpqr.mrenclave}}
+  environment:
+    OTP_SINGLE_USE: "/root/single_run/once"
+    OTP_ACCOUNT_NAME: {{yaml-quote scone_account}}
+    OTP_ACCOUNT_LOGIN: {{yaml-quote scone_user}}
+    OTP_SECRET: $$SCONE::otp_secret$$
+    OTP_OUTPUT_FILE: "/root/qrcode.svg"
+{{#if reset}}
+    OTP_RESET: "TRUE"
+{{/if}}
+  pwd: "/root"
+"#;
+
+const OTPQR_IMAGE: &str = r#"- name: otpqr_image
+  volumes:
+    - name: single_run_{{volume_version}}
+      path: /root/single_run
+"#;
+
+const SINGLE_RUN_EXPORT: &str = r#"- name: single_run_{{volume_version}}
+  export:
+    - session: {{to}}
+"#;
+
+const SINGLE_RUN_IMPORT: &str = r#"- name: single_run_{{volume_version}}
+  import:
+    session: {{from}}
+    volume: single_run_{{volume_version}}
+"#;
+
+/// partials of this crate - name and template
+const PARTIALS: [(&str, &str); 8] = [
+    ("session_header", SESSION_HEADER),
+    ("creator_access_policy", CREATOR_ACCESS_POLICY),
+    ("namespace", NAMESPACE),
+    ("attestation", ATTESTATION),
+    ("otpqr_service", OTPQR_SERVICE),
+    ("otpqr_image", OTPQR_IMAGE),
+    ("single_run_export", SINGLE_RUN_EXPORT),
+    ("single_run_import", SINGLE_RUN_IMPORT),
+];
+
+/// partials registered by `register_partial`
+static USER_PARTIALS: RwLock<BTreeMap<String, String>> = RwLock::new(BTreeMap::new());
+
+handlebars_helper!(yaml_quote: |value: str| serde_json::to_string(value).unwrap_or_default());
+handlebars_helper!(base64: |value: str| BASE64.encode(value.as_bytes()));
+handlebars_helper!(sha256: |value: str| HEXLOWER.encode(&Sha256::digest(value.as_bytes())));
+
+/// add partial `name` to the partials available in all session templates - replaces a partial with the same name
+pub fn register_partial(name: &str, template: &str) -> Result<()> {
+    // make sure that the partial can be parsed before we store it
+    Handlebars::new().register_partial(name, template)
+        .map_err(|e| Error::Template { session: format!("partial {}", name), message: e.to_string() })?;
+    USER_PARTIALS.write().unwrap_or_else(|e| e.into_inner()).insert(name.to_string(), template.to_string());
+    Ok(())
+}
+
+/// all partials - name and template: the partials of this crate, replaced or extended by `register_partial`
+pub(crate) fn partials() -> BTreeMap<String, String> {
+    let mut partials : BTreeMap<String, String> = PARTIALS.iter().map(|(name, template)| (name.to_string(), template.to_string())).collect();
+    partials.extend(USER_PARTIALS.read().unwrap_or_else(|e| e.into_inner()).clone());
+    partials
+}
+
+/// Handlebars registry used to render session templates: strict mode, no HTML escaping,
+/// and all partials and helpers of this crate plus the partials registered with `register_partial`.
+pub fn template_registry() -> Result<Handlebars<'static>> {
+    let mut reg = Handlebars::new();
+    reg.set_strict_mode(true);
+    reg.register_escape_fn(no_escape);
+    reg.register_helper("yaml-quote", Box::new(yaml_quote));
+    reg.register_helper("base64", Box::new(base64));
+    reg.register_helper("sha256", Box::new(sha256));
+    for (name, template) in partials() {
+        reg.register_partial(&name, template)
+            .map_err(|e| Error::Template { session: format!("partial {}", name), message: e.to_string() })?;
+    }
+    Ok(reg)
+}
+
+/// helpers of handlebars and of this crate - the arguments of a helper are variables, the helper itself is not
+const HELPERS: [&str; 19] = ["if", "unless", "each", "with", "lookup", "log", "eq", "ne", "gt", "gte", "lt", "lte", "and", "or", "not", "len",
+    "yaml-quote", "base64", "sha256"];
+
+/// Variable a session template refers to - or defines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateVariable {
+    pub name: String,               // path of the variable, e.g., mrenclaves.otpqr.mrenclave
+    pub line: usize,                // line of the template that refers to (or defines) the variable (starting at 1)
+    pub partial: Option<String>,    // partial included in `line` that refers to (or defines) the variable - if any
+}
+
+impl fmt::Display for TemplateVariable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.name)?;
+        match &self.partial {
+            Some(partial) => write!(f, " (in partial '{}')", partial),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Result of `check_template`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TemplateCheck {
+    pub missing: Vec<TemplateVariable>,     // variables the template refers to but `data` does not define
+    pub unused: Vec<TemplateVariable>,      // partial parameters and block parameters the template defines but never refers to
+    pub unused_fields: Vec<String>,         // fields of `data` the template does not refer to
+}
+
+/// check - without rendering - the variables `template` (and the partials it includes) refers to against
+/// the JSON proxy `data` of a state. The template is parsed by handlebars: a template that cannot be parsed
+/// is not checked - rendering reports the syntax error. Variables inside `{{#if}}` / `{{#unless}}` blocks
+/// (including their conditions and `{{else}}` branches) may be undefined. Variables inside `{{#each}}` /
+/// `{{#with}}` blocks are not checked since they are relative to the block's context - except `@root` paths.
+pub fn check_template(template: &str, data: &Value) -> TemplateCheck {
+    let mut checker = Checker { data, partials: partials(), missing: vec![], unused: vec![], used: BTreeSet::new(), referred: vec![BTreeSet::new()] };
+    match Template::compile(template) {
+        Ok(template) => checker.check(&template, &Scope::default(), 1, &mut vec![]),
+        Err(e) => info!("Template is not checked since it cannot be parsed: {}", e),
+    }
+    let unused_fields = match data {
+        Value::Object(fields) => fields.keys().filter(|field| !checker.used.contains(*field)).cloned().collect(),
+        _ => vec![],
+    };
+    TemplateCheck { missing: checker.missing, unused: checker.unused, unused_fields }
+}
+
+/// Context in which the elements of a template are checked.
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    bound: BTreeSet<String>,            // names defined by hash parameters of the including partials and by block parameters
+    include: Option<(usize, String)>,   // line of the outermost template and name of the partial being checked
+    optional: bool,                     // inside `{{#if}}` / `{{#unless}}`: variables may be undefined
+    opaque: bool,                       // inside `{{#each}}` / `{{#with}}`: variables are relative to another context
+}
+
+struct Checker<'a> {
+    data: &'a Value,
+    partials: BTreeMap<String, String>,
+    missing: Vec<TemplateVariable>,
+    unused: Vec<TemplateVariable>,
+    used: BTreeSet<String>,             // fields of `data` referred to
+    referred: Vec<BTreeSet<String>>,    // per partial or block with parameters: first segments of all paths referred to
+}
+
+impl Checker<'_> {
+    /// check the elements of `template` - `line` is the line of the element that contains `template`
+    fn check(&mut self, template: &Template, scope: &Scope, line: usize, stack: &mut Vec<String>) {
+        for (i, element) in template.elements.iter().enumerate() {
+            let line = match &scope.include {
+                Some((line, _)) => *line,
+                None => template.mapping.get(i).map_or(line, |mapping| mapping.0),
+            };
+            match element {
+                TemplateElement::Expression(helper) | TemplateElement::HtmlExpression(helper) => {
+                    let name = helper.name.as_name().unwrap_or("");
+                    if helper.params.is_empty() && helper.hash.is_empty() && !HELPERS.contains(&name) {
+                        self.parameter(&helper.name, scope, line);
+                    } else {
+                        self.arguments(&helper.params, &helper.hash, scope, line);
+                    }
+                },
+                TemplateElement::HelperBlock(helper) => self.block(helper, scope, line, stack),
+                TemplateElement::PartialExpression(partial) => self.partial(partial, scope, line, stack),
+                TemplateElement::PartialBlock(partial) => {
+                    self.partial(partial, scope, line, stack);
+                    // the content is only rendered if the partial does not exist or includes `{{> @partial-block}}`
+                    if let Some(content) = &partial.template {
+                        self.check(content, &Scope { optional: true, ..scope.clone() }, line, stack);
+                    }
+                },
+                // comments, text and decorators - e.g., inline partials that are rendered in the context of their use
+                _ => {},
+            }
+        }
+    }
+
+    /// check block helper `helper` in `line`
+    fn block(&mut self, helper: &HelperTemplate, scope: &Scope, line: usize, stack: &mut Vec<String>) {
+        let name = helper.name.as_name().unwrap_or("");
+        let optional = name == "if" || name == "unless";
+        let conditional = Scope { optional: scope.optional || optional, ..scope.clone() };
+        self.arguments(&helper.params, &helper.hash, &conditional, line);
+        let parameters : Vec<String> = match &helper.block_param {
+            Some(BlockParam::Single(parameter)) => parameter.as_name().into_iter().map(str::to_string).collect(),
+            Some(BlockParam::Pair((first, second))) => [first, second].into_iter().filter_map(|p| p.as_name()).map(str::to_string).collect(),
+            _ => vec![],
+        };
+        if let Some(content) = &helper.template {
+            let mut inner = Scope { opaque: scope.opaque || name == "each" || name == "with", ..conditional.clone() };
+            inner.bound.extend(parameters.iter().cloned());
+            self.referred.push(BTreeSet::new());
+            self.check(content, &inner, line, stack);
+            self.close(&parameters, scope.include.as_ref().map(|(_, partial)| partial.clone()), line);
+        }
+        // `{{else}}` is rendered in the current context
+        if let Some(inverse) = &helper.inverse {
+            self.check(inverse, &conditional, line, stack);
+        }
+    }
+
+    /// check partial `partial` included in `line` - and the partial itself unless it is rendered with a context of its own
+    fn partial(&mut self, partial: &DecoratorTemplate, scope: &Scope, line: usize, stack: &mut Vec<String>) {
+        self.arguments(&partial.params, &partial.hash, scope, line);
+        let name = match &partial.name {
+            Parameter::Name(name) => name.clone(),
+            Parameter::Literal(Value::String(name)) => name.clone(),
+            _ => return,    // dynamic partial
+        };
+        if !partial.params.is_empty() || scope.opaque || stack.contains(&name) {
+            return
+        }
+        let template = match self.partials.get(&name).map(|partial| Template::compile(partial)) {
+            Some(Ok(template)) => template,
+            _ => return,
+        };
+        let parameters : Vec<String> = partial.hash.keys().cloned().collect();
+        let mut inner = Scope { include: Some((line, name.clone())), ..scope.clone() };
+        inner.bound.extend(parameters.iter().cloned());
+        stack.push(name.clone());
+        self.referred.push(BTreeSet::new());
+        self.check(&template, &inner, line, stack);
+        stack.pop();
+        self.close(&parameters, Some(name), line);
+    }
+
+    /// end of the partial or block that defines `parameters` in `line` - reports the parameters never referred to
+    fn close(&mut self, parameters: &[String], partial: Option<String>, line: usize) {
+        let referred = self.referred.pop().unwrap_or_default();
+        let mut parameters = parameters.to_vec();
+        parameters.sort();
+        for name in parameters.into_iter().filter(|name| !referred.contains(name)) {
+            self.unused.push(TemplateVariable { name, line, partial: partial.clone() });
+        }
+        // the enclosing partial or block may define names referred to inside
+        if let Some(outer) = self.referred.last_mut() {
+            outer.extend(referred);
+        }
+    }
+
+    /// check the arguments of a helper or partial
+    fn arguments<'p>(&mut self, params: &'p [Parameter], hash: impl IntoIterator<Item = (&'p String, &'p Parameter)>, scope: &Scope, line: usize) {
+        for parameter in params.iter().chain(hash.into_iter().map(|(_, value)| value)) {
+            self.parameter(parameter, scope, line);
+        }
+    }
+
+    /// check argument `parameter` - variables are referred to, subexpressions are checked and literals skipped
+    fn parameter(&mut self, parameter: &Parameter, scope: &Scope, line: usize) {
+        match parameter {
+            Parameter::Path(_) => self.refer(parameter.as_name().unwrap_or(""), scope, line),
+            Parameter::Subexpression(subexpression) => {
+                let params = subexpression.params().cloned().unwrap_or_default();
+                let hash = subexpression.hash().cloned().unwrap_or_default();
+                self.arguments(&params, &hash, scope, line);
+            },
+            _ => {},
+        }
+    }
+
+    /// `token` is a reference to a variable - record it as used and, unless `scope` allows it, as missing if not defined
+    fn refer(&mut self, token: &str, scope: &Scope, line: usize) {
+        if token.starts_with("../") || (token.starts_with('@') && !token.starts_with("@root.")) {
+            return
+        }
+        let (path, root) = match token.strip_prefix("@root.") {
+            Some(path) => (path, true),
+            None => (token.strip_prefix("this.").or_else(|| token.strip_prefix("./")).unwrap_or(token), false),
+        };
+        let segments = path_segments(path);
+        let first = match segments.first() {
+            Some(first) if first != "this" && !first.is_empty() => first,
+            _ => return,
+        };
+        if !root {
+            if let Some(referred) = self.referred.last_mut() {
+                referred.insert(first.clone());
+            }
+            if scope.opaque || scope.bound.contains(first) {
+                return
+            }
+        }
+        self.used.insert(first.clone());
+        if scope.optional || (scope.opaque && !root) {
+            return
+        }
+        let mut value = Some(self.data);
+        for segment in &segments {
+            value = value.and_then(|v| match v {
+                Value::Object(fields) => fields.get(segment),
+                Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)),
+                _ => None,
+            });
+        }
+        let partial = scope.include.as_ref().map(|(_, partial)| partial.clone());
+        let variable = TemplateVariable { name: token.to_string(), line, partial };
+        if value.is_none() && !self.missing.contains(&variable) {
+            self.missing.push(variable);
+        }
+    }
+}
+
+/// segments of a path like `mrenclaves.[generate-key-pair].mrenclave` or `mrenclaves/otpqr/mrenclave`
+fn path_segments(path: &str) -> Vec<String> {
+    let mut segments = vec![];
+    let mut segment = String::new();
+    let mut bracket = false;
+    for c in path.chars() {
+        match c {
+            '[' if !bracket => bracket = true,
+            ']' if bracket => bracket = false,
+            '.' | '/' if !bracket => segments.push(std::mem::take(&mut segment)),
+            c => segment.push(c),
+        }
+    }
+    segments.push(segment);
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// missing and unused variables of `template` as `line: name` - checked against a small state
+    fn check(template: &str) -> (Vec<String>, Vec<String>) {
+        let data = json!({ "namespace": "ns", "items": ["a", "b"], "mrenclaves": { "otpqr": { "mrenclave": "m" } } });
+        let check = check_template(template, &data);
+        let lines = |variables: Vec<TemplateVariable>| variables.iter().map(|v| format!("{}: {}", v.line, v.name)).collect();
+        (lines(check.missing), lines(check.unused))
+    }
+
+    #[test]
+    fn scopes_of_blocks_and_paths() {
+        let cases : [(&str, &[&str], &[&str]); 14] = [
+            ("{{namespace}}\n{{baz}}", &["2: baz"], &[]),
+            ("{{mrenclaves.otpqr.mrenclave}} {{mrenclaves.[otpqr].digest}}", &["1: mrenclaves.[otpqr].digest"], &[]),
+            // variables guarded by #if / #unless may be undefined - in all branches
+            ("{{#if baz}}{{qux}}{{/if}}", &[], &[]),
+            ("{{#unless baz}}\n{{qux}}\n{{else}}{{quux}}{{/unless}}", &[], &[]),
+            ("{{#if namespace}}{{else if baz}}{{qux}}{{else}}{{quux}}{{/if}}\n{{qux}}", &["2: qux"], &[]),
+            // #each / #with: the argument is required, the content is relative to the block's context
+            ("{{#each items}}{{this}} {{name}} {{@index}}{{/each}}", &[], &[]),
+            ("{{#each missing}}\n{{name}}{{/each}}", &["1: missing"], &[]),
+            ("{{#with mrenclaves}}{{otpqr.mrenclave}} {{../namespace}} {{@root.namespace}} {{@root.baz}}{{/with}}", &["1: @root.baz"], &[]),
+            ("{{#each items as |item i|}}\n{{item}}\n{{/each}}", &[], &["1: i"]),
+            ("{{#each items}}{{else}}\n{{baz}}{{/each}}", &["2: baz"], &[]),
+            // this / ./ refer to the current context
+            ("{{this.namespace}} {{./namespace}} {{this.baz}}\n{{this}}", &["1: this.baz"], &[]),
+            // helpers: the arguments are variables, literals and helper names are not
+            ("{{yaml-quote namespace}} {{base64 \"text\"}} {{sha256 (yaml-quote baz)}} {{#if (eq namespace 1)}}{{/if}}", &["1: baz"], &[]),
+            // comments and escaped tags are not checked
+            ("{{! {{baz}} }}\n{{!-- {{qux}} --}}\n\\{{quux}}", &[], &[]),
+            // templates that cannot be parsed are reported when rendering
+            ("{{#if namespace}}{{baz}}", &[], &[]),
+        ];
+        for (template, missing, unused) in cases {
+            assert_eq!(check(template), (missing.iter().map(|s| s.to_string()).collect(), unused.iter().map(|s| s.to_string()).collect()), "{}", template);
+        }
+    }
+
+    #[test]
+    fn partials_are_checked_with_their_parameters() {
+        register_partial("test_service", "name: {{name}}\n{{#if enabled}}{{mrenclave}}{{/if}}\n{{command}} {{namespace}}\n{{baz}}").unwrap();
+        register_partial("test_cycle", "{{> test_cycle}}{{baz}}").unwrap();
+        let cases : [(&str, &[&str], &[&str]); 5] = [
+            // hash parameters are defined in the partial - parameters the partial never refers to are unused
+            ("x:\n  {{> test_service name=namespace command=\"run\" enabled=true unused=namespace}}", &["2: baz"], &["2: unused"]),
+            // parameter values are variables of the including template
+            ("{{> test_service name=qux command=1}}", &["1: qux", "1: baz"], &[]),
+            // a partial with a context of its own refers to fields of that context
+            ("{{> test_service mrenclaves}}", &[], &[]),
+            ("{{> test_cycle}}", &["1: baz"], &[]),
+            ("{{#> unknown_partial}}\n{{baz}}{{/unknown_partial}}", &[], &[]),
+        ];
+        for (template, missing, unused) in cases {
+            assert_eq!(check(template), (missing.iter().map(|s| s.to_string()).collect(), unused.iter().map(|s| s.to_string()).collect()), "{}", template);
+        }
+        let check = check_template("{{> test_service name=qux command=1}}", &json!({ "namespace": "ns", "other": 1 }));
+        assert_eq!(check.missing[1].partial.as_deref(), Some("test_service"));
+        assert_eq!(check.unused_fields, vec!["other"]);
+    }
+
+    #[test]
+    fn builtin_partials_refer_to_defined_variables() {
+        let data = json!({ "namespace": "ns", "volume_version": 1, "scone_account": "a", "scone_user": "u",
+            "mrenclaves": { "otpqr": { "mrenclave": "m" } }, "predecessor_key": "#", "predecessor": "" });
+        let template = "{{> namespace}}\n{{> otpqr_service command=\"run\" reset=true}}\n{{> otpqr_image}}\n{{> single_run_export to=namespace}}";
+        let check = check_template(template, &data);
+        assert_eq!((check.missing, check.unused, check.unused_fields), (vec![], vec![], vec![]));
+    }
+}
@@ -0,0 +1,425 @@
+//! Commands shared by the tools that manage an OTP secret in a namespace (`otp_policy`, `cosign_policy`).
+//!
+//! The states of these tools differ, but they have the fields of `OtpState` in common: the commands of `OtpTool`
+//! access them via the JSON proxy of the tool's state - the other fields are kept as they are.
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::{audit, audit_log, apply_sessions, attest_cas, check_attestation, encrypts_state, import_state_env, read_bundle, read_session,
+    read_state, read_state_env, render_session, restore_session, to_json_value, write_bundle, write_state, AttestationPolicy,
+    AuditEntry, AuditKind, Bundle, CasAttestation, CasClient, Error, Init, Measurements, Result, SessionSpec, SessionVersion,
+    Versioned, verify_audit_log, STATE_ENV_FIELDS};
+
+/// state file of the tools - relative to the working directory
+pub const STATE_FILE: &str = "state.js";
+
+/// OTP secret and session hashes of a volume version - recorded before roll-forward or roll-back replace the secret
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub secret: String,             // base32 encoded OTP secret of this volume version
+    pub session_hash: String,       // hash of session when the secret was replaced
+    pub session_hash2: String,      // hash of session2 when the secret was replaced
+}
+
+impl Snapshot {
+    /// snapshot of the current volume version of `state`
+    pub fn of(state: &OtpState) -> Snapshot {
+        Snapshot { secret: state.secret.clone(), session_hash: state.session_hash.clone(), session_hash2: state.session_hash2.clone() }
+    }
+}
+
+/// Fields that the states of all OTP tools have in common - missing fields are empty.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct OtpState {
+    pub namespace: String,          // name of the namespace
+    pub namespace_hash: String,     // last known hash of the namespace session
+    pub session: String,            // name of the session that defines the OTP secret - including namespace
+    pub session_hash: String,
+    pub session_version: u64,       // volume version of session
+    pub session2: String,           // name of the session that uses the OTP for access control
+    pub session_hash2: String,
+    pub session_version2: u64,      // volume version of session2
+    pub volume_version: u64,        // version of the single_run volume - changes with the OTP secret
+    pub secret: String,             // base32 encoded OTP secret
+    pub mrenclaves: Measurements,
+    pub cas_addr: String,
+    pub cas_attestation: Option<CasAttestation>,
+    pub fingerprints: BTreeMap<String, String>,
+    pub snapshots: BTreeMap<u64, Snapshot>,
+}
+
+impl OtpState {
+    /// the common fields of `state`
+    pub fn of<T : Serialize>(state: &T) -> Result<Self> {
+        serde_json::from_value(to_json_value(state)?).map_err(|source| Error::Json { context: "state".to_string(), source })
+    }
+
+    /// set the common fields of `state` to this - the other fields of `state` are kept
+    pub fn apply_to<T : Serialize + for<'de> Deserialize<'de>>(&self, state: &mut T) -> Result<()> {
+        let mut j = to_json_value(&*state)?;
+        if let (Some(fields), serde_json::Value::Object(common)) = (j.as_object_mut(), to_json_value(self)?) {
+            fields.extend(common);
+        }
+        *state = serde_json::from_value(j).map_err(|source| Error::Json { context: "state".to_string(), source })?;
+        Ok(())
+    }
+}
+
+/// Session templates of an OTP tool - rendered with the fields of its state.
+#[derive(Debug, Clone, Default)]
+pub struct SessionTemplates {
+    pub namespace: String,
+    pub session: String,
+    pub session2: String,
+    pub files: BTreeMap<String, String>,    // customizable policy files the templates were read from - by file name
+}
+
+/// session templates of a tool - determined from (and checked against) its state
+pub type TemplatesOf<T> = Box<dyn Fn(&T) -> Result<SessionTemplates>>;
+
+/// Tool that manages an OTP secret in a namespace - the state of type `T` must have the fields of `OtpState`.
+pub struct OtpTool<T> {
+    pub name: &'static str,         // name of the tool, e.g., otp_policy
+    pub session: &'static str,      // name of session in the namespace, e.g., otpqr-x
+    pub session2: &'static str,     // name of session2 in the namespace, e.g., otpqr-reset
+    pub templates: TemplatesOf<T>,  // session templates - checked against the state
+}
+
+/// Earlier volume version that `OtpTool::roll_back` can restore: its OTP secret and - if found in the predecessor
+/// chains - the descriptions of session and session2 of that version.
+#[derive(Debug, Clone)]
+pub struct Restorable {
+    pub secret: String,
+    pub session: Option<SessionVersion>,
+    pub session2: Option<SessionVersion>,
+}
+
+/// volume version (volume single_run_N) and OTP secret (secret otp_secret) of a session description
+fn volume_and_secret(version: &SessionVersion) -> Option<(u64, Option<String>)> {
+    let session = version.session().ok()?;
+    let volume = session.volumes.iter().find_map(|v| v.name.strip_prefix("single_run_").and_then(|n| n.parse().ok()))?;
+    Some((volume, session.secret("otp_secret").and_then(|s| s.value.clone())))
+}
+
+/// volume versions that can be restored - from the snapshots in the state and the predecessor chains in CAS.
+/// The current volume version is not included.
+pub fn restorable_versions(state: &OtpState) -> BTreeMap<u64, Restorable> {
+    let mut versions : BTreeMap<u64, Restorable> = state.snapshots.iter()
+        .map(|(v, snapshot)| (*v, Restorable { secret: snapshot.secret.clone(), session: None, session2: None }))
+        .collect();
+    let histories = CasClient::from_cas_config(&state.cas_addr)
+        .and_then(|cas| Ok((cas.session_history(&state.session)?, cas.session_history(&state.session2)?)));
+    match histories {
+        Ok((history, history2)) => {
+            // the histories start with the current version: we take the newest version of each volume version
+            for version in history {
+                if let Some((v, Some(secret))) = volume_and_secret(&version) {
+                    let restorable = versions.entry(v).or_insert(Restorable { secret: secret.clone(), session: None, session2: None });
+                    if restorable.session.is_none() && restorable.secret == secret {
+                        restorable.session = Some(version);
+                    }
+                }
+            }
+            for version in history2 {
+                if let Some((v, _)) = volume_and_secret(&version) {
+                    if let Some(restorable) = versions.get_mut(&v).filter(|r| r.session2.is_none()) {
+                        restorable.session2 = Some(version);
+                    }
+                }
+            }
+        },
+        Err(e) => warn!("Cannot read the predecessor chains from CAS - only snapshots can be restored: {}", e),
+    }
+    versions.remove(&state.volume_version);
+    versions
+}
+
+/// the snapshots keep earlier OTP secrets: they end up in plain text in an unencrypted state file
+pub fn warn_unencrypted_snapshots() {
+    if !encrypts_state(STATE_FILE) {
+        warn!("{} is not encrypted - the earlier OTP secrets kept for roll-back are stored in plain text. Run 'encrypt-state' to encrypt {}.", STATE_FILE, STATE_FILE);
+    }
+}
+
+impl<T : Serialize + for<'de> Deserialize<'de> + Init + Versioned> OtpTool<T> {
+    /// set the common fields of `state` to `common` and write the state
+    fn save(&self, state: &mut T, common: &OtpState) -> Result<()> {
+        common.apply_to(state)?;
+        write_state(&*state, STATE_FILE)
+    }
+
+    /// restore the OTP secret of volume version `version` - default: the latest version before the current one -
+    /// or list the versions that can be restored. The sessions of that version are taken from the predecessor chains
+    /// in CAS or rendered from a snapshot. The state is written after each session that was changed in CAS. If session2
+    /// cannot be restored, session is restored to its description before the roll-back.
+    pub fn roll_back(&self, version: Option<u64>, list: bool) -> Result<()> {
+        let mut state : T = read_state(STATE_FILE)?;
+        let mut common = OtpState::of(&state)?;
+        check_attestation(common.cas_attestation.as_ref(), &common.cas_addr)?;
+        let versions = restorable_versions(&common);
+        if list {
+            println!("Current volume version: {}", common.volume_version);
+            for (v, restorable) in &versions {
+                match (&restorable.session, &restorable.session2) {
+                    (Some(session), Some(session2)) => println!("  {}: sessions {} and {} in CAS", v, session.hash, session2.hash),
+                    _ => println!("  {}: snapshot - sessions are rendered from the templates", v),
+                }
+            }
+            return Ok(());
+        }
+        let target = version.or_else(|| versions.keys().copied().filter(|v| *v < common.volume_version).max())
+            .ok_or_else(|| Error::Restore { session: common.session.clone(), message: "there is no earlier volume version".to_string() })?;
+        let restorable = versions.get(&target)
+            .ok_or_else(|| Error::Restore { session: common.session.clone(), message: format!("volume version {} is neither in the predecessor chain nor in a snapshot - see roll-back --list", target) })?;
+
+        // keep the current secret - we can roll back to it as well
+        common.snapshots.insert(common.volume_version, Snapshot::of(&common));
+        warn_unencrypted_snapshots();
+        self.save(&mut state, &common)?;
+        if let Err(e) = self.restore(&mut state, &mut common, target, restorable) {
+            audit::record(AuditEntry::new(AuditKind::RollBack, &common.session).with_detail(&format!("volume version {}", target)).with_success(false));
+            return Err(e)
+        }
+
+        // remove existing files
+        let _ = fs::remove_file("single_run/once");
+        let _ = fs::remove_file("single_run/volume.fspf");
+        audit::record(AuditEntry::new(AuditKind::RollBack, &common.session).with_hash(&common.session_hash).with_detail(&format!("volume version {}", target)));
+        println!("Restored the OTP secret of volume version {}. Authenticators of that version work again - use 'gen-qr-code' to add it to an authenticator.", target);
+        Ok(())
+    }
+
+    /// restore the sessions of volume version `target` from `restorable` and write the state
+    fn restore(&self, state: &mut T, common: &mut OtpState, target: u64, restorable: &Restorable) -> Result<()> {
+        let mut restored = common.clone();
+        restored.secret = restorable.secret.clone();
+        restored.volume_version = target;
+        match (&restorable.session, &restorable.session2) {
+            (Some(session), Some(session2)) => {
+                info!("Restoring volume version {} from sessions {} and {}", target, session.hash, session2.hash);
+                let previous = read_session(&common.session)?;
+                common.session_hash = restore_session(&common.session, &session.yaml)?.trim().to_string();
+                self.save(state, common)?;
+                match restore_session(&common.session2, &session2.yaml) {
+                    Ok(hash) => common.session_hash2 = hash.trim().to_string(),
+                    Err(source) => {
+                        let rolled_back = self.undo_restore(state, common, previous);
+                        return Err(Error::Apply { session: common.session2.clone(), rolled_back, source: Box::new(source) })
+                    },
+                }
+            },
+            _ => {
+                info!("Restoring volume version {} from snapshot", target);
+                let templates = (self.templates)(state)?;
+                let specs = [
+                    SessionSpec::new(&common.session, &common.session_hash, &templates.session),
+                    SessionSpec::new(&common.session2, &common.session_hash2, &templates.session2),
+                ];
+                // the sessions are rendered with the restored secret - apply_sessions restores them on failure
+                restored.apply_to(state)?;
+                for applied in apply_sessions(&specs, &*state, true)? {
+                    if applied.session == common.session {
+                        common.session_hash = applied.hash;
+                    } else {
+                        common.session_hash2 = applied.hash;
+                    }
+                }
+            },
+        }
+        common.secret = restored.secret;
+        common.volume_version = target;
+        common.session_version = target;
+        common.session_version2 = target;
+        // the fingerprints are determined again by the next create
+        common.fingerprints.remove(&common.session);
+        common.fingerprints.remove(&common.session2);
+        self.save(state, common)
+    }
+
+    /// restore session to its description `previous` before the roll-back - returns the sessions restored
+    fn undo_restore(&self, state: &mut T, common: &mut OtpState, previous: Option<(String, String)>) -> Vec<String> {
+        let Some((previous, _)) = previous else {
+            warn!("Cannot undo the roll-back of session {}: it did not exist before", common.session);
+            return vec![]
+        };
+        match restore_session(&common.session, &previous).and_then(|hash| {
+            common.session_hash = hash.trim().to_string();
+            self.save(state, common)
+        }) {
+            Ok(()) => vec![common.session.clone()],
+            Err(e) => {
+                warn!("Cannot undo the roll-back of session {}: {}", common.session, e);
+                vec![]
+            },
+        }
+    }
+
+    /// write the state, the sessions rendered from the state with their hashes, the MRENCLAVEs and the policy files
+    /// to the encrypted bundle `file` (see `write_bundle`)
+    pub fn export(&self, file: &str) -> Result<()> {
+        let state : T = read_state(STATE_FILE)?;
+        let common = OtpState::of(&state)?;
+        let templates = (self.templates)(&state)?;
+        let mut bundle = Bundle::new(self.name, &state)?.with_mrenclaves(&common.mrenclaves);
+        for (name, content) in &templates.files {
+            bundle = bundle.with_template(name, content);
+        }
+        let sessions = [
+            (&common.namespace, &common.namespace_hash, &templates.namespace),
+            (&common.session, &common.session_hash, &templates.session),
+            (&common.session2, &common.session_hash2, &templates.session2),
+        ];
+        for (name, hash, template) in sessions.into_iter().filter(|(_, hash, _)| !hash.is_empty()) {
+            bundle = bundle.with_session(name, hash, &render_session(name, template, &state, None)?);
+        }
+        write_bundle(&bundle, file)?;
+        if bundle.templates.is_empty() {
+            println!("Exported {} and {} session(s) to {}. The bundle contains the OTP secret: keep it and its passphrase safe.", STATE_FILE, bundle.sessions.len(), file);
+        } else {
+            println!("Exported {}, {} policy file(s) and {} session(s) to {}. The bundle contains the OTP secret: keep it and its passphrase safe.",
+                STATE_FILE, bundle.templates.len(), bundle.sessions.len(), file);
+        }
+        Ok(())
+    }
+
+    /// restore the state and the policy files of the bundle `file` written by `export` of this tool. Unless `force`
+    /// is set, neither a state that refers to an existing namespace nor policy files that differ are replaced.
+    pub fn import(&self, file: &str, force: bool) -> Result<()> {
+        let current = OtpState::of(&read_state::<T>(STATE_FILE)?)?;
+        if !current.namespace_hash.is_empty() && !force {
+            println!("{} already refers to namespace {}. Specify --force to replace it.", STATE_FILE, current.namespace);
+            return Ok(());
+        }
+        let bundle = read_bundle(file)?;
+        if bundle.tool != self.name {
+            return Err(Error::Bundle { path: file.to_string(), message: format!("exported by {} - not by {}", bundle.tool, self.name) })
+        }
+        // policy files are written to the current directory only - and customized policies are not overwritten by accident
+        for (name, content) in &bundle.templates {
+            if Path::new(name).file_name().map(|n| n.to_string_lossy() != name.as_str()).unwrap_or(true) {
+                return Err(Error::Bundle { path: file.to_string(), message: format!("invalid policy file name '{}'", name) })
+            }
+            if !force && Path::new(name).exists() && fs::read_to_string(name).map_err(|source| Error::Io { path: name.clone(), source })? != *content {
+                println!("Policy file {} differs from the one in {}. Specify --force to overwrite it.", name, file);
+                return Ok(());
+            }
+        }
+        let state : T = bundle.state()?;
+        for (name, content) in &bundle.templates {
+            fs::write(name, content).map_err(|source| Error::Io { path: name.clone(), source })?;
+            info!("Written policy to file {}.", name);
+        }
+        write_state(&state, STATE_FILE)?;
+        println!("Imported namespace {} exported at {} from {}:", OtpState::of(&state)?.namespace, bundle.created, file);
+        for (name, session) in &bundle.sessions {
+            println!("  {} (hash {})", name, session.hash);
+        }
+        println!("Run 'attest-cas' on this machine before updating the sessions.");
+        Ok(())
+    }
+
+    /// verify the audit log `file` - default: see `audit_log` - and print its entries
+    pub fn verify_log(&self, file: Option<String>) -> Result<()> {
+        let file = file.unwrap_or_else(audit_log);
+        let entries = verify_audit_log(&file)?;
+        for e in &entries {
+            println!("{} {} {:?} {} {} {} {}", e.timestamp, e.user, e.kind, e.session, if e.hash.is_empty() { "-" } else { &e.hash },
+                if e.detail.is_empty() { "-" } else { &e.detail }, if e.success { "ok" } else { "FAILED" });
+        }
+        println!("Audit log {} is intact: {} entries.", file, entries.len());
+        Ok(())
+    }
+
+    /// print all versions of the sessions - following the predecessor hashes back to the first version.
+    /// With `yaml`, the session descriptions are printed too.
+    pub fn history(&self, yaml: bool) -> Result<()> {
+        let state : T = read_state(STATE_FILE)?;
+        let common = OtpState::of(&state)?;
+        check_attestation(common.cas_attestation.as_ref(), &common.cas_addr)?;
+        let cas = CasClient::from_cas_config(&common.cas_addr)?;
+        for name in [&common.namespace, &common.session, &common.session2] {
+            println!("Session {}:", name);
+            let history = cas.session_history(name)?;
+            for (i, version) in history.iter().enumerate() {
+                let changes = match history.get(i + 1) {
+                    Some(older) => format!("changed: {}", version.changed_fields(older).join(", ")),
+                    None => "first version".to_string(),
+                };
+                println!("  {} (version {}) {}", version.hash, version.version().as_deref().unwrap_or("?"), changes);
+                if yaml {
+                    println!("{}", version.yaml);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// attest CAS `cas` according to `policy` and record its address and attestation in the state
+    pub fn attest_cas(&self, cas: &str, policy: &AttestationPolicy) -> Result<()> {
+        let mut state : T = read_state(STATE_FILE)?;
+        let mut common = OtpState::of(&state)?;
+        let attestation = attest_cas(cas, policy)?;
+        println!("Attested CAS {} at {}.", cas, attestation.attested_at);
+        common.cas_addr = cas.to_string();
+        common.cas_attestation = Some(attestation);
+        self.save(&mut state, &common)
+    }
+
+    /// adopt the namespace and the session (`CLI_SESSION`) with their hashes of the `state.env` file `file` - without
+    /// `CLI_SESSION`, our sessions are (re-)created in that namespace.
+    /// Unless `force` is set, a namespace that already exists is not replaced.
+    pub fn import_state_env(&self, file: &str, force: bool) -> Result<()> {
+        let mut state : T = read_state(STATE_FILE)?;
+        let current = OtpState::of(&state)?;
+        if !current.namespace_hash.is_empty() && !force {
+            println!("{} already refers to namespace {}. Specify --force to replace it.", STATE_FILE, current.namespace);
+            return Ok(());
+        }
+        let vars = read_state_env(file)?;
+        if !vars.contains_key("NS") && !vars.contains_key("NAMESPACE") {
+            println!("{} does not define a namespace (NS): nothing imported.", file);
+            return Ok(());
+        }
+        let imported = import_state_env(&mut state, &vars, STATE_ENV_FIELDS)?;
+        // the session is adopted with its hash - the other session is (re-)created in the imported namespace
+        let mut common = OtpState::of(&state)?;
+        if !imported.iter().any(|field| field == "session") {
+            common.session = format!("{}/{}", common.namespace, self.session);
+            common.session_hash.clear();
+        }
+        common.session2 = format!("{}/{}", common.namespace, self.session2);
+        common.session_hash2.clear();
+        self.save(&mut state, &common)?;
+        println!("Imported {} from {}.", imported.join(", "), file);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Default)]
+    struct State {
+        namespace: String,
+        secret: String,
+        otp_image: String,
+        snapshots: BTreeMap<u64, Snapshot>,
+    }
+
+    #[test]
+    fn common_fields_are_accessed_via_the_json_proxy() {
+        let mut state = State { namespace: "ns".to_string(), otp_image: "otpqr:scone".to_string(), ..State::default() };
+        let mut common = OtpState::of(&state).unwrap();
+        assert_eq!(common.namespace, "ns");
+        common.secret = "S".to_string();
+        common.snapshots.insert(1, Snapshot { secret: "R".to_string(), ..Snapshot::default() });
+        common.apply_to(&mut state).unwrap();
+        assert_eq!((state.secret.as_str(), state.otp_image.as_str(), state.snapshots[&1].secret.as_str()), ("S", "otpqr:scone", "R"));
+    }
+}
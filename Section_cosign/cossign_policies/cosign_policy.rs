@@ -7,8 +7,6 @@
 //! clap-verbosity-flag = "*"
 //! env_logger = "*"
 //! log = "*"
-//! rand = "*"
-//! data-encoding = "*"
 //! scone_cli = { version = "*", path="../../Section_OTP/scone_cli" }
 //! shells ="*"
 //! serde = "*"
@@ -18,13 +16,12 @@
 use clap::{ArgGroup, Parser};
 use clap_verbosity_flag::{Verbosity, ErrorLevel};
 use env_logger;
-use log::{info, error};
-use data_encoding::BASE32_NOPAD;
-use scone_cli::{write_state,read_state,check_mrenclave,create_session,Init, random_name};
+use log::{info, warn, error};
+use scone_cli::{write_state,read_state,check_mrenclaves, check_attestation, AttestationPolicy, CasAttestation, Tolerate, Init, random_name, Secret, Result, Error, DEFAULT_CAS_ADDR, lint_templates, plan_sessions, confirm, dry_run_sessions, encrypt_state_file, release_state_locks, Versioned, Migrations, Measurement, Measurements, uses_measurement, to_json_value, check_template, apply_sessions, SessionSpec, AuditEntry, AuditKind, get_otp, OtpState, OtpTool, SessionTemplates, Snapshot, warn_unencrypted_snapshots, record};
 use shells::*;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{read_to_string, write, remove_file, create_dir_all};
-use std::io;
-use std::io::Write;
+use std::process;
 use std::path::Path;
 use serde::{Deserialize, Serialize};
 use users::get_current_username;
@@ -44,13 +41,76 @@ pub struct State {
     pub session_hash2: String,      // hash of 2nd session
     pub session_version2: u64,      // version of session that  we have created last ; if different from volume_version, we need to update the session
 
-    pub mrenclave: String,          // MrEnclave of the QR code generator
+    pub mrenclave: String,          // MrEnclave of the QR code generator - for templates that do not use mrenclaves
+    pub mrenclaves: Measurements,   // MrEnclave and image digest of each service, e.g., {{mrenclaves.sign.mrenclave}}
+    pub needs_update: BTreeSet<String>, // sessions that refer to a service whose MrEnclave changed
     pub volume_version: u64,        // triggers a key roll by increasing this version
     pub otp_image: String,          // name of the image to generate the QR code
     pub otp_binary: String,         // binary in that image that we need to execute
     pub scone_user: String,         // name of the user that executes this program
     pub scone_account : String,     // some name used in your authenticator
-    pub secret: String,             // base32 encoded secret - for now in clear text. We need to protect this!
+    pub secret: String,             // base32 encoded secret - use encrypt-state to protect state.js
+    pub cas_addr: String,           // address of CAS - set by attest-cas
+    pub cas_attestation: Option<CasAttestation>, // certificate and attestation metadata of CAS - set by attest-cas
+    pub fingerprints: BTreeMap<String, String>,   // fingerprint of each session when it was created / updated last
+    pub snapshots: BTreeMap<u64, Snapshot>,       // OTP secret of each earlier volume version - see roll-back
+}
+
+impl Versioned for State {
+    const SCHEMA_VERSION: u32 = 6;
+
+    fn migrations() -> Migrations {
+        // version 0: state files written before we introduced schema versions - same layout as version 1
+        // version 1: one MRENCLAVE for all services - add a measurement for each service
+        // version 2: sessions that need an update since an image was rebuilt were not tracked
+        // version 3: CAS was not attested - we assumed scone-cas.cf
+        // version 4: no fingerprints of the sessions - sessions are updated only if forced until they are known
+        // version 5: no snapshots - earlier OTP secrets can only be restored from the predecessor chains
+        Migrations::new()
+            .add(0, |_| Ok(()))
+            .add(1, |state| {
+                let image = state["otp_image"].as_str().unwrap_or("otpqr:scone").to_string();
+                let binary = state["otp_binary"].as_str().unwrap_or("/bin/otpqr").to_string();
+                let mut mrenclaves = measurements(&image, &binary);
+                for service in ["otpqr", "test"] {
+                    if let Some(m) = mrenclaves.get_mut(service) {
+                        m.mrenclave = state["mrenclave"].as_str().unwrap_or_default().to_string();
+                    }
+                }
+                state["mrenclaves"] = to_json_value(mrenclaves)?;
+                Ok(())
+            })
+            .add(2, |state| {
+                state["needs_update"] = to_json_value(BTreeSet::<String>::new())?;
+                Ok(())
+            })
+            .add(3, |state| {
+                state["cas_addr"] = DEFAULT_CAS_ADDR.into();
+                state["cas_attestation"] = to_json_value(None::<CasAttestation>)?;
+                Ok(())
+            })
+            .add(4, |state| {
+                state["fingerprints"] = to_json_value(BTreeMap::<String, String>::new())?;
+                Ok(())
+            })
+            .add(5, |state| {
+                state["snapshots"] = to_json_value(BTreeMap::<u64, Snapshot>::new())?;
+                Ok(())
+            })
+    }
+}
+
+/// image, binary and environment of each service of the sessions - the MRENCLAVEs are determined by `check_mrenclaves`
+fn measurements(otp_image: &str, otp_binary: &str) -> Measurements {
+    let otpqr = Measurement::new(otp_image, otp_binary);
+    let cosign = Measurement::new("cosign:scone", "/go/bin/cosign");
+    Measurements::from([
+        ("otpqr".to_string(), otpqr.clone()),
+        ("test".to_string(), otpqr),
+        ("generate-key-pair".to_string(), cosign.clone()),
+        ("sign".to_string(), cosign.clone().with_env("SCONE_HEAP", "1G")),
+        ("verify".to_string(), cosign.with_env("SCONE_HEAP", "1G")),
+    ])
 }
 
 impl Init for State {
@@ -61,7 +121,7 @@ impl Init for State {
             None        => random_name(10),
         };
 
-        let secret : [u8 ; 32] = rand::random();
+        let secret = Secret::binary(32);
         let state = State {
             session: format!("{}/cosign", ns),
             session2: format!("{}/cosign-reset", ns), // Needed?
@@ -70,10 +130,12 @@ impl Init for State {
             scone_account: "SCONE cosign".to_string(),
             otp_image: "otpqr:scone".to_string(),
             otp_binary: "/bin/otpqr".to_string(),
-            secret: BASE32_NOPAD.encode(&secret).into(),
+            mrenclaves: measurements("otpqr:scone", "/bin/otpqr"),
+            secret: secret.to_base32().to_string(),
+            cas_addr: DEFAULT_CAS_ADDR.to_string(),
             ..Default::default()
         };
-        info!("Initialized state is {:?}", state);
+        info!("Initialized state for namespace {}", state.namespace);
         state
     }
 }
@@ -94,6 +156,15 @@ enum Commands {
         /// For example, in case you updated the session templates.
         #[clap(long)]
         force: bool,
+
+        /// Show the changes of all sessions and ask for confirmation before updating them.
+        #[clap(long)]
+        plan: bool,
+
+        /// Render all sessions to directory DIR (with a manifest.json) instead of creating them - contacts neither CAS nor docker.
+        #[clap(long, value_name = "DIR", conflicts_with = "plan")]
+        dry_run: Option<String>,
+
         #[clap(flatten)]
         verbose: Verbosity<ErrorLevel>,
     },
@@ -169,6 +240,40 @@ enum Commands {
         #[clap(long)]
         force: bool,
 
+        /// Show the changes of all sessions and ask for confirmation before replacing the OTP secret.
+        #[clap(long)]
+        plan: bool,
+
+        /// Render all sessions with a new OTP secret to directory DIR (with a manifest.json) - the state is not changed.
+        #[clap(long, value_name = "DIR", conflicts_with = "plan")]
+        dry_run: Option<String>,
+
+        #[clap(flatten)]
+        verbose: Verbosity<ErrorLevel>,
+    },
+
+    #[structopt(about = "Restore the OTP secret of an earlier volume version, e.g., after a roll-forward went wrong. \
+    The sessions of that version are taken from the predecessor chains in CAS - or rendered from a snapshot in \
+    state.js - and created as new versions. Afterwards, authenticators of that version work again.")]
+    #[clap(group(ArgGroup::new("f0rce").required(true).args(&["force", "list"])))]
+    RollBack {
+        /// Prefix of the file that contains the policies - used to render the sessions of a snapshot.
+        /// The default files are policy_namespace.yml, policy_remote.yml and policy_admin.yml
+        #[clap(long, default_value="policy")]
+        prefix: String,
+
+        /// Volume version to restore - default: the latest version before the current one
+        #[clap(long)]
+        version: Option<u64>,
+
+        /// List the volume versions that can be restored
+        #[clap(long)]
+        list: bool,
+
+        /// Must be specified to restore a version since this replaces the current OTP secret
+        #[clap(long)]
+        force: bool,
+
         #[clap(flatten)]
         verbose: Verbosity<ErrorLevel>,
     },
@@ -189,6 +294,122 @@ enum Commands {
         verbose: Verbosity<ErrorLevel>,
     },
 
+    #[structopt(about = "Check the policies for cross-reference errors without contacting CAS. Run this after customizing the policies.")]
+    Lint {
+        /// Prefix of the file that contains the policies. We add a number and suffix .yml.
+        /// The default files are policy_namespace.yml, policy_remote.yml and policy_admin.yml
+        #[clap(long, default_value="policy")]
+        prefix: String,
+
+        #[clap(flatten)]
+        verbose: Verbosity<ErrorLevel>,
+    },
+
+    #[structopt(about = "Encrypt the state file 'state.js'. The passphrase is read from SCONE_STATE_PASSPHRASE or asked for. \
+    Afterwards, all commands need this passphrase.")]
+    EncryptState {
+        #[clap(flatten)]
+        verbose: Verbosity<ErrorLevel>,
+    },
+
+    #[structopt(about = "Adopt the namespace, the session (CLI_SESSION) and their hashes of a state.env file written by the bash exercises.")]
+    ImportStateEnv {
+        /// state.env file to import, e.g., ../../Exercise6/state.env
+        #[clap(default_value = "state.env")]
+        file: String,
+
+        /// Replace the namespace even if state.js already refers to an existing namespace.
+        #[clap(long)]
+        force: bool,
+
+        #[clap(flatten)]
+        verbose: Verbosity<ErrorLevel>,
+    },
+
+    #[structopt(about = "Export state.js, the policy files and the rendered sessions with their hashes and MRENCLAVEs to a bundle \
+    encrypted with a passphrase (read from SCONE_BUNDLE_PASSPHRASE or asked for). Keep the bundle: state.js is the only record of the namespace.")]
+    Export {
+        /// bundle to write
+        #[clap(default_value = "state.bundle")]
+        file: String,
+
+        /// Prefix of the file that contains the policies. We add a number and suffix .yml.
+        /// The default files are policy_namespace.yml, policy_remote.yml and policy_admin.yml
+        #[clap(long, default_value="policy")]
+        prefix: String,
+
+        #[clap(flatten)]
+        verbose: Verbosity<ErrorLevel>,
+    },
+
+    #[structopt(about = "Restore state.js and the policy files from a bundle written by 'export', e.g., on another admin machine.")]
+    Import {
+        /// bundle to import
+        #[clap(default_value = "state.bundle")]
+        file: String,
+
+        /// Replace state.js even if it already refers to an existing namespace and overwrite policy files that differ.
+        #[clap(long)]
+        force: bool,
+
+        #[clap(flatten)]
+        verbose: Verbosity<ErrorLevel>,
+    },
+
+    #[structopt(about = "Verify the hash chain of the audit log and list its entries: who created sessions, attested CAS, \
+    ran OTP-protected commands or rolled the OTP secret.")]
+    VerifyLog {
+        /// audit log to verify - default: SCONE_AUDIT_LOG or audit.log
+        file: Option<String>,
+
+        #[clap(flatten)]
+        verbose: Verbosity<ErrorLevel>,
+    },
+
+    #[structopt(about = "Attest CAS and record its certificate in state.js. Commands that operate on sessions require an attested CAS.")]
+    AttestCas {
+        /// Address of CAS
+        #[clap(long, default_value = DEFAULT_CAS_ADDR)]
+        cas: String,
+
+        /// Expected MRENCLAVE of CAS, e.g., as published for the public CAS
+        #[clap(long)]
+        mrenclave: Option<String>,
+
+        /// Expected MRSIGNER of CAS
+        #[clap(long)]
+        mrsigner: Option<String>,
+
+        /// Expected product ID of CAS - requires --mrsigner
+        #[clap(long, requires = "mrsigner")]
+        isvprodid: Option<u16>,
+
+        /// Minimal security version of CAS - requires --mrsigner
+        #[clap(long, requires = "mrsigner")]
+        isvsvn: Option<u16>,
+
+        /// Do not check the signer of CAS - only for testing: the public CAS is not signed
+        #[clap(long, requires = "mrenclave")]
+        ignore_signer: bool,
+
+        /// Accept a deviation of CAS - can be given several times
+        #[clap(long, value_name = "DEVIATION", possible_values = ["outdated-tcb", "configuration-needed", "software-hardening-needed", "debug-mode"])]
+        tolerate: Vec<String>,
+
+        #[clap(flatten)]
+        verbose: Verbosity<ErrorLevel>,
+    },
+
+    #[structopt(about = "Print all versions of the policies. We follow the predecessor hashes back to the first version.")]
+    History {
+        /// Print the session descriptions too. Note that these contain the OTP secret!
+        #[clap(long)]
+        yaml: bool,
+
+        #[clap(flatten)]
+        verbose: Verbosity<ErrorLevel>,
+    },
+
 }
 
 fn init_logger(verbose : Verbosity<ErrorLevel>) {
@@ -197,44 +418,89 @@ fn init_logger(verbose : Verbosity<ErrorLevel>) {
 
 fn main() {
     let cmd = Commands::parse();
-    match cmd {
-        Commands::Create{ prefix, force, verbose } => { init_logger(verbose); create_command(&prefix, force) },
+    let result = match cmd {
+        Commands::Create{ prefix, force, plan, dry_run, verbose } => { init_logger(verbose); create_command(&prefix, force, plan, dry_run) },
         Commands::AddAuthenticator{ otp, verbose } => { init_logger(verbose); add_authenticator(otp) },
         Commands::GenQRCode{ verbose } => { init_logger(verbose); gen_qr_code() },
         Commands::TestQRCode{ verbose } => { init_logger(verbose); test_qr_code() },
-        Commands::RollForward{ prefix, force, verbose } => { init_logger(verbose); roll_forward(&prefix, force) },
+        Commands::RollForward{ prefix, force, plan, dry_run, verbose } => { init_logger(verbose); roll_forward(&prefix, force, plan, dry_run) },
+        Commands::RollBack{ prefix, version, list, force: _, verbose } => { init_logger(verbose); tool(&prefix).roll_back(version, list) },
         Commands::GenPolicies{ prefix, force, verbose } => { init_logger(verbose); write_policies(&prefix, force) },
         Commands::GenKeypair{ otp, verbose } => { init_logger(verbose); gen_keypair(otp) },
         Commands::SignImage{ otp, image, verbose } => { init_logger(verbose); sign_image(otp, image) },
         Commands::VerifyImage{ otp, image, verbose } => { init_logger(verbose); verify_image(otp, image) },
+        Commands::Lint{ prefix, verbose } => { init_logger(verbose); lint_command(&prefix) },
+        // history, attest-cas and import-state-env do not use the templates - the default policy files will do
+        Commands::History{ yaml, verbose } => { init_logger(verbose); tool("policy").history(yaml) },
+        Commands::AttestCas{ cas, mrenclave, mrsigner, isvprodid, isvsvn, ignore_signer, tolerate, verbose } => {
+            init_logger(verbose);
+            let policy = AttestationPolicy {
+                mrenclave, mrsigner, isvprodid, isvsvn, ignore_signer,
+                tolerate: tolerate.iter().filter_map(|t| Tolerate::from_name(t)).collect(),
+            };
+            tool("policy").attest_cas(&cas, &policy)
+        },
+        Commands::EncryptState{ verbose } => { init_logger(verbose); encrypt_state_command() },
+        Commands::ImportStateEnv{ file, force, verbose } => { init_logger(verbose); tool("policy").import_state_env(&file, force) },
+        Commands::Export{ file, prefix, verbose } => { init_logger(verbose); tool(&prefix).export(&file) },
+        Commands::Import{ file, force, verbose } => { init_logger(verbose); tool("policy").import(&file, force) },
+        Commands::VerifyLog{ file, verbose } => { init_logger(verbose); tool("policy").verify_log(file) },
+    };
+    release_state_locks();
+    if let Err(e) = result {
+        error!("{}", e);
+        process::exit(1);
     }
 }
 
-fn write_policies(prefix : &str, force : bool) {
+fn write_policies(prefix : &str, force : bool) -> Result<()> {
     let filename = format!("{}_namespace.yml", prefix);
-    write_file(&filename, force, SESSION_TEMPLATE0);
+    write_file(&filename, force, SESSION_TEMPLATE0)?;
     let filename = format!("{}_admin.yml", prefix);
-    write_file(&filename, force, SESSION_TEMPLATE1);
+    write_file(&filename, force, SESSION_TEMPLATE1)?;
     let filename = format!("{}_remote.yml", prefix);
-    write_file(&filename, force, SESSION_TEMPLATE2);
+    write_file(&filename, force, SESSION_TEMPLATE2)
 }
 
-fn write_file(filename: &str, force: bool, content: &str) {
+fn write_file(filename: &str, force: bool, content: &str) -> Result<()> {
     if force || !Path::new(filename).exists() {
-        write(filename, content).unwrap_or_else( | _ | panic !("Unable to write file '{}'", filename));
+        write(filename, content).map_err(|source| Error::Io { path: filename.to_string(), source })?;
         info!("Written policy to file {}.", filename);
     } else {
         error!("File {} already exists. Use --force to overwrite.", filename)
     }
+    Ok(())
+}
+
+fn read_file(filename: &str) -> Result<String> {
+    read_to_string(filename).map_err(|source| Error::Io { path: filename.to_string(), source })
 }
 
-fn read_policies(prefix : &str) ->  (String, String, String) {
+fn read_policies(prefix : &str) -> Result<(String, String, String)> {
     let namespace = format!("{}_namespace.yml", prefix);
     let admin = format!("{}_admin.yml", prefix);
     let remote = format!("{}_remote.yml", prefix);
-    (read_to_string(&namespace).expect("namespace policy"),
-     read_to_string(&admin).expect("admin policy"),
-     read_to_string(&remote).expect("admin policy"))
+    Ok((read_file(&namespace)?, read_file(&admin)?, read_file(&remote)?))
+}
+
+// check that the (customized) policies only refer to variables defined by the state - reports all undefined
+// variables of a policy file with their line numbers instead of failing on the first one while rendering.
+// Parameters the policies define but never refer to are logged.
+fn check_policies(prefix : &str, policies: &(String, String, String), state: &State) -> Result<()> {
+    let mut data = to_json_value(state)?;
+    data["predecessor_key"] = "#".into();
+    data["predecessor"] = "".into();
+    let (namespace, admin, remote) = policies;
+    for (suffix, template) in [("namespace", namespace), ("admin", admin), ("remote", remote)] {
+        let check = check_template(template, &data);
+        if !check.missing.is_empty() {
+            return Err(Error::TemplateVariables { session: format!("{}_{}.yml", prefix, suffix), missing: check.missing, unused: check.unused })
+        }
+        for variable in &check.unused {
+            warn!("{}_{}.yml defines unused parameter {}", prefix, suffix, variable);
+        }
+    }
+    Ok(())
 }
 
 
@@ -242,46 +508,16 @@ static SESSION_TEMPLATE0 : &str = r##"#
 # Simple Namespace Template
 # - Only creator has access to this namespace
 #
-name: {{namespace}}
-version: "0.3"
-{{predecessor_key}}: {{predecessor}}
-
-access_policy:
-  read:
-    - CREATOR
-  update:
-    - CREATOR
-  create_sessions:
-    - CREATOR
+{{> namespace}}
 "##;
 
 static SESSION_TEMPLATE1 : &str = r#"
-name: {{session}}
-version: "0.3"
-{{predecessor_key}}: {{predecessor}}
-
-access_policy:
-    read:
-    - CREATOR
-    update:
-    - CREATOR
-    create_sessions:
-    - CREATOR
+{{> session_header name=session}}
+
+{{> creator_access_policy}}
 
 services:
-    - name: otpqr
-      image_name: otpqr_image
-## enable for release mode:
-#     attestation:
-#      - mrenclave:
-#        - {{mrenclave}}
-      environment:
-        OTP_SINGLE_USE: "/root/single_run/once"
-        OTP_ACCOUNT_NAME: "{{scone_account}}"
-        OTP_ACCOUNT_LOGIN: "{{scone_user}}"
-        OTP_SECRET: $$SCONE::otp_secret$$
-        OTP_OUTPUT_FILE: "/root/qrcode.svg"
-      pwd: "/root"
+    {{> otpqr_service}}
     - name: test
       image_name: otpqr_image
       environment:
@@ -299,15 +535,10 @@ security:
     # tolerate: [debug-mode, hyperthreading, outdated-tcb]
 
 volumes:
-    - name: single_run_{{volume_version}}
-      export:
-        - session: {{session2}}
+    {{> single_run_export to=session2}}
 
 images:
-    - name: otpqr_image
-      volumes:
-        - name: single_run_{{volume_version}}
-          path: /root/single_run
+    {{> otpqr_image}}
 
 secrets:
     - name: otp_secret
@@ -322,36 +553,22 @@ secrets:
 // - requires OTP to be able to add the generator
 
 static SESSION_TEMPLATE2 : &str = r#"
-name: {{session2}}
-version: "0.3"
-{{predecessor_key}}: {{predecessor}}
-
-access_policy:
-  read:
-    - CREATOR
-  update:
-    - CREATOR
-  create_sessions:
-    - CREATOR
+{{> session_header name=session2}}
+
+{{> creator_access_policy}}
 
 services:
     - name: generate-key-pair
       command: cosign generate-key-pair
       image_name: cosign_image
-## enable for release mode:
-#     attestation:
-#      - mrenclave:
-#        - {{mrenclave}}
+      {{> attestation mrenclave=mrenclaves.generate-key-pair.mrenclave}}
       environment:
         COSIGN_PASSWORD: $$SCONE::cosign_password$$
       pwd: "/root/cosign_keys"
     - name: sign
       command: cosign sign --key /root/cosign_keys/cosign.key @@1
       image_name: cosign_image
-## enable for release mode:
-#     attestation:
-#      - mrenclave:
-#        - {{mrenclave}}
+      {{> attestation mrenclave=mrenclaves.sign.mrenclave}}
       environment:
         COSIGN_PASSWORD: $$SCONE::cosign_password$$
         COSIGN_DOCKER_MEDIA_TYPES: 1
@@ -371,10 +588,7 @@ services:
     - name: verify
       command: cosign verify --key /root/cosign_keys/cosign.pub @@1
       image_name: cosign_image
-## enable for release mode:
-#     attestation:
-#      - mrenclave:
-#        - {{mrenclave}}
+      {{> attestation mrenclave=mrenclaves.verify.mrenclave}}
       environment:
         COSIGN_PASSWORD: $$SCONE::cosign_password$$
         COSIGN_DOCKER_MEDIA_TYPES: 1
@@ -391,20 +605,7 @@ services:
         PATH: /go/bin:/usr/local/go/bin:/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin
         GOPATH: /go
       pwd: "/root"
-    - name: otpqr
-      image_name: otpqr_image
-      command: /bin/otpqr
-    #    attestation:
-    #      - mrenclave:
-    #        - $MRENCLAVE
-      environment:
-        OTP_SINGLE_USE: "/root/single_run/once"
-        OTP_ACCOUNT_NAME: "{{scone_account}}"
-        OTP_ACCOUNT_LOGIN: "{{scone_user}}"
-        OTP_SECRET: $$SCONE::otp_secret$$
-        OTP_OUTPUT_FILE: "/root/qrcode.svg"
-        OTP_RESET: "TRUE"
-      pwd: "/root"
+    {{> otpqr_service command="/bin/otpqr" reset=true}}
 
 security:
   attestation:
@@ -415,20 +616,14 @@ security:
 
 volumes:
   - name: cosign_volume
-  - name: single_run_{{volume_version}}
-    import:
-      session: {{session}}
-      volume: single_run_{{volume_version}}
+  {{> single_run_import from=session}}
 
 images:
   - name: cosign_image
     volumes:
       - name: cosign_volume
         path: /root/cosign_keys
-  - name: otpqr_image
-    volumes:
-      - name: single_run_{{volume_version}}
-        path: /root/single_run
+  {{> otpqr_image}}
 
 
 secrets:
@@ -442,153 +637,281 @@ secrets:
 "#;
 
 
-fn roll_forward(prefix : &String, force: bool) {
+fn roll_forward(prefix : &String, force: bool, plan: bool, dry_run: Option<String>) -> Result<()> {
 
-// increment version by 1!
-    let mut state : State = read_state("state.js");
-    state.volume_version += 1;
+// keep the current secret - roll-back can restore it - and use a volume version that was not used before
+    let mut state : State = read_state("state.js")?;
+    state.snapshots.insert(state.volume_version, Snapshot::of(&OtpState::of(&state)?));
+    warn_unencrypted_snapshots();
+    state.volume_version = state.snapshots.keys().copied().max().unwrap_or_default().max(state.volume_version) + 1;
 // create a new secret
-    let secret : [u8 ; 32] = rand::random();
-    state.secret = BASE32_NOPAD.encode(&secret).into();
-    info!("{:?}", state);
-    write_state(&state, "state.js");
+    let secret = Secret::binary(32);
+    state.secret = secret.to_base32().to_string();
+    let policies = read_policies(prefix)?;
+    check_policies(prefix, &policies, &state)?;
+    if let Some(dir) = dry_run {
+        return render_sessions(&state, &policies, force, &dir);
+    }
+    require_attested(&state)?;
+    info!("Created new OTP secret for volume version {}", state.volume_version);
+    if plan && !confirm_plan(&state, &policies, force)? {
+        println!("Aborted: the OTP secret was not replaced.");
+        return Ok(());
+    }
+    write_state(&state, "state.js")?;
 
 // remove existing files
     let _ = remove_file("single_run/once");
     let _ = remove_file("single_run/volume.fspf");
     info!("Updating policies...");
-    create_command(prefix, force);
+    let detail = format!("volume version {}", state.volume_version);
+    if let Err(e) = create_command(prefix, force, false, None) {
+        // some sessions might use the new secret already
+        record(AuditEntry::new(AuditKind::RollForward, &state.session).with_detail(&detail).with_success(false));
+        return Err(e)
+    }
+    let state : State = read_state("state.js")?;
+    record(AuditEntry::new(AuditKind::RollForward, &state.session).with_hash(&state.session_hash).with_detail(&format!("volume version {}", state.volume_version)));
+    Ok(())
 }
 
-
-fn gen_qr_code() {
-    let state : State = read_state("state.js");
+fn gen_qr_code() -> Result<()> {
+    let state : State = read_state("state.js")?;
+    require_attested(&state)?;
 
 // run as docker command
-    let (code, stdout, stderr) = sh!(r#"docker run --rm -w "/root" -v "$PWD:/root" -e SCONE_CAS_ADDR=scone-cas.cf -e SCONE_CONFIG_ID={}/otpqr {} {} > qr.output"#, state.session, state.otp_image, state.otp_binary);
+    let (code, stdout, stderr) = sh!(r#"docker run --rm -w "/root" -v "$PWD:/root" -e SCONE_CAS_ADDR={} -e SCONE_CONFIG_ID={}/otpqr {} {} > qr.output"#, state.cas_addr, state.session, state.otp_image, state.otp_binary);
+    record(AuditEntry::new(AuditKind::DockerRun, &state.session).with_hash(&state.session_hash).with_detail("gen-qr-code").with_success(code == 0));
     info!("Command: {}\nCode: {}\n{}\n", state.otp_binary, code, stdout);
     if code != 0 {
         error!("ERROR: executing 'gen-QR-code'. Code: {}\nError output:\n{}", code, stderr);
     } else {
         println!("Written QR code to file qrcode.svg.\n 1. Please 'open qrcode.svg' and scan qr code to initialize your authentication.\n 2. Remove qrcode.svg using: 'shred -n 3 -z -u qrcode.svg'\n")
     }
+    Ok(())
 }
 
 
-fn test_qr_code() {
-    let state : State = read_state("state.js");
+fn test_qr_code() -> Result<()> {
+    let state : State = read_state("state.js")?;
+    require_attested(&state)?;
 
 // run as docker command
-    let (code, stdout, stderr) = sh!(r#"docker run --rm -w "/root" -v "$PWD:/root" -e SCONE_CAS_ADDR=scone-cas.cf -e SCONE_CONFIG_ID={}/test {} {} > qr.output"#, state.session, state.otp_image, state.otp_binary);
+    let (code, stdout, stderr) = sh!(r#"docker run --rm -w "/root" -v "$PWD:/root" -e SCONE_CAS_ADDR={} -e SCONE_CONFIG_ID={}/test {} {} > qr.output"#, state.cas_addr, state.session, state.otp_image, state.otp_binary);
     info!("Command: {}\nCode: {}\n{}\n", state.otp_binary, code, stdout);
     if code != 0 {
         error!("ERROR: executing 'test-QR-code'. Code: {}\nError output:\n{}", code, stderr);
     } else {
         println!("Written test QR code to file test.svg.\n- This cannot be used for authorization.\n")
     }
+    Ok(())
+}
+// the sessions as applied by create_command - the sessions are applied in the order of their dependencies,
+// the customized policies might add dependencies
+fn session_specs(state: &State, policies: &(String, String, String)) -> [SessionSpec; 3] {
+    let (namespace_template, session_template, session_template2) = policies;
+    [
+        SessionSpec::new(&state.namespace, &state.namespace_hash, namespace_template),
+        SessionSpec::new(&state.session, &state.session_hash, session_template).with_force(state.session_version != state.volume_version),
+        SessionSpec::new(&state.session2, &state.session_hash2, session_template2).with_force(state.session_version2 != state.volume_version),
+    ].map(|spec| {
+        let fingerprint = state.fingerprints.get(&spec.name).cloned().unwrap_or_default();
+        let force = spec.force || state.needs_update.contains(&spec.name);
+        spec.with_force(force).with_fingerprint(&fingerprint)
+    })
+}
+
+// the commands shared with otp_policy - the templates are read from the policy files with prefix `prefix`
+fn tool(prefix: &str) -> OtpTool<State> {
+    let prefix = prefix.to_string();
+    OtpTool {
+        name: "cosign_policy",
+        session: "cosign",
+        session2: "cosign-reset",
+        templates: Box::new(move |state| {
+            let policies = read_policies(&prefix)?;
+            check_policies(&prefix, &policies, state)?;
+            let (namespace, session, session2) = policies;
+            let files = BTreeMap::from([
+                (format!("{}_namespace.yml", prefix), namespace.clone()),
+                (format!("{}_admin.yml", prefix), session.clone()),
+                (format!("{}_remote.yml", prefix), session2.clone()),
+            ]);
+            Ok(SessionTemplates { namespace, session, session2, files })
+        }),
+    }
+}
+
+// show what create_command would change and ask for confirmation
+fn confirm_plan(state: &State, policies: &(String, String, String), force: bool) -> Result<bool> {
+    for plan in plan_sessions(&session_specs(state, policies), state, force)? {
+        println!("{}", plan);
+    }
+    confirm("Apply these changes?")
 }
-fn create_command(prefix : &String, force: bool) {
+
+// render all sessions to directory `dir` - without contacting CAS or docker
+fn render_sessions(state: &State, policies: &(String, String, String), force: bool, dir: &str) -> Result<()> {
+    let (namespace_template, session_template, session_template2) = policies;
+    lint_templates(&[namespace_template, session_template, session_template2], state)?;
+    let sessions = dry_run_sessions(&session_specs(state, policies), state, force, dir)?;
+    sessions.iter().for_each(|s| println!("{:?}: {} -> {}/{}", s.action, s.session, dir, s.file));
+    Ok(())
+}
+
+// mark the sessions whose templates refer to a service with a changed MRENCLAVE - they are updated even if they exist
+fn mark_changed_sessions(state: &mut State, changed: &[String], templates: [&String; 3]) {
+    let sessions = [state.namespace.clone(), state.session.clone(), state.session2.clone()];
+    for (session, template) in sessions.into_iter().zip(templates) {
+        let affected = changed.iter().any(|service| uses_measurement(template, "mrenclaves", service)
+            || (service == "otpqr" && template.contains("{{mrenclave}}")));
+        if affected {
+            warn!("Session {} refers to a changed MRENCLAVE: it needs to be updated", session);
+            state.needs_update.insert(session);
+        }
+    }
+}
+
+fn create_command(prefix : &String, force: bool, plan: bool, dry_run: Option<String>) -> Result<()> {
     // template for define OTP secret
 //    let session_template = SESSION_TEMPLATE1;
 //    let session_template2 = SESSION_TEMPLATE2;
 //    let namespace_template = SESSION_TEMPLATE0;
 
-    let (namespace_template, session_template, session_template2) = read_policies(&prefix);
+    let policies = read_policies(prefix)?;
+    let (namespace_template, session_template, session_template2) = &policies;
 
     // create "volume"
     let _ = create_dir_all("single_run");
     let _ = create_dir_all("cosign_keys");
 
-    let mut state : State = read_state("state.js"); // default: provide init state
-    // retrieve MRENCLAVE from otp_image
-    check_mrenclave(&mut state, "mrenclave", "otp_image", "otp_binary", force).expect("Failed to determine MRENCLAVE. Does image exist?"); // j, "mrenclave",
-    state.namespace_hash = create_session(&state.namespace, &state.namespace_hash, &namespace_template, &state, force).expect("Creating namespace");
-
-    let force = force || state.session_version != state.volume_version;  // check if we need to update the session?
-    state.session_hash = create_session(&state.session, &state.session_hash, &session_template, &state, force).expect("Creating session");
+    let mut state : State = read_state("state.js")?; // default: provide init state
+    check_policies(prefix, &policies, &state)?;
+    if let Some(dir) = dry_run {
+        for (service, m) in state.mrenclaves.iter().filter(|(_, m)| m.mrenclave.is_empty()) {
+            warn!("MRENCLAVE of service {} ({} in {}) not yet determined: rendering sessions with an empty MRENCLAVE", service, m.binary, m.image);
+        }
+        return render_sessions(&state, &policies, force, &dir);
+    }
+    require_attested(&state)?;
+    // retrieve the MRENCLAVE of each service - again, if its image was rebuilt
+    let changed = check_mrenclaves(&mut state, "mrenclaves", force)?;
+    state.mrenclave = state.mrenclaves.get("otpqr").map(|m| m.mrenclave.clone()).unwrap_or_default();
+    if !changed.is_empty() {
+        mark_changed_sessions(&mut state, &changed, [namespace_template, session_template, session_template2]);
+        write_state(&state, "state.js")?; // remember the sessions to update - even if we do not update them now
+    }
+    lint_templates(&[namespace_template, session_template, session_template2], &state)?;
+    if plan && !confirm_plan(&state, &policies, force)? {
+        println!("Aborted: no session was updated.");
+        return Ok(());
+    }
+    for applied in apply_sessions(&session_specs(&state, &policies), &state, force)? {
+        if applied.session == state.namespace {
+            state.namespace_hash = applied.hash;
+        } else if applied.session == state.session {
+            state.session_hash = applied.hash;
+        } else {
+            state.session_hash2 = applied.hash;
+        }
+        state.needs_update.remove(&applied.session);
+        state.fingerprints.insert(applied.session, applied.fingerprint);
+    }
     info!("Session hash = {}", state.session_hash);
+    info!("Session hash2 = {}", state.session_hash2);
     state.session_version = state.volume_version;
-
-    let force = force || state.session_version2 != state.volume_version; // check if we need to update the session?
-    state.session_hash2 = create_session(&state.session2, &state.session_hash2, &session_template2, &state, force).expect("Creating session2");
-    info!("Session hash2 = {}", state.session_hash);
     state.session_version2 = state.volume_version;
 
-    write_state(&state, "state.js");
+    write_state(&state, "state.js")
 }
 
-fn add_authenticator(otp: Option<String>) {
-    let state : State = read_state("state.js"); // default: provide init state
-    let otp = get_otp(otp);
+fn lint_command(prefix : &str) -> Result<()> {
+    let state : State = read_state("state.js")?;
+    let policies = read_policies(prefix)?;
+    check_policies(prefix, &policies, &state)?;
+    let (namespace_template, session_template, session_template2) = &policies;
+    lint_templates(&[namespace_template, session_template, session_template2], &state)?;
+    println!("No problems found.");
+    Ok(())
+}
+
+fn encrypt_state_command() -> Result<()> {
+    if encrypt_state_file("state.js")? {
+        println!("Encrypted state.js. Note that backups of state.js might still contain the OTP secret in clear text.");
+    } else {
+        println!("state.js is already encrypted.");
+    }
+    Ok(())
+}
+
+fn add_authenticator(otp: Option<String>) -> Result<()> {
+    let state : State = read_state("state.js")?; // default: provide init state
+    require_attested(&state)?;
+    let otp = get_otp(otp)?;
 
     info!("Got OTP {}", otp);
-    let (code, stdout, stderr) = sh!(r#"docker run --rm -w "/root" -v "$PWD:/root" -e "SCONE_CAS_ADDR=scone-cas.cf" -e "SCONE_CONFIG_ID={}/otpqr@{}" otpqr:scone /bin/otpqr > qr.output"#, state.session2, otp);
+    let (code, stdout, stderr) = sh!(r#"docker run --rm -w "/root" -v "$PWD:/root" -e "SCONE_CAS_ADDR={}" -e "SCONE_CONFIG_ID={}/otpqr@{}" otpqr:scone /bin/otpqr > qr.output"#, state.cas_addr, state.session2, otp);
+    record(AuditEntry::new(AuditKind::DockerRun, &state.session2).with_hash(&state.session_hash2).with_detail("add-authenticator").with_success(code == 0));
     info!("Command: returns code: {}\n{}\n", code, stdout);
     if code != 0 {
         error!("ERROR: executing '/bin/otpqr.rs'. Code: {}\nError output:\n{}", code, stderr);
     } else {
         println!("Written QR code to file qrcode.svg.\n 1. Please 'open qrcode.svg' and scan qr code to initialize your authentication.\n 2. Remove qrcode.svg using: 'shred -n 3 -z -u qrcode.svg'\n")
     }
+    Ok(())
 }
 
 
-fn gen_keypair(otp: Option<String>) {
-    let state : State = read_state("state.js"); // default: provide init state
-    let otp = get_otp(otp);
+fn gen_keypair(otp: Option<String>) -> Result<()> {
+    let state : State = read_state("state.js")?; // default: provide init state
+    require_attested(&state)?;
+    let otp = get_otp(otp)?;
 
     info!("Got OTP {}", otp);
-    let (code, stdout, stderr) = sh!(r#"docker run --rm -w "/root" -v "$PWD:/root" -e "SCONE_CAS_ADDR=scone-cas.cf" -e "SCONE_CONFIG_ID={}/generate-key-pair@{}" cosign:scone  /go/bin/cosign > qr.output"#, state.session2, otp);
+    let (code, stdout, stderr) = sh!(r#"docker run --rm -w "/root" -v "$PWD:/root" -e "SCONE_CAS_ADDR={}" -e "SCONE_CONFIG_ID={}/generate-key-pair@{}" cosign:scone  /go/bin/cosign > qr.output"#, state.cas_addr, state.session2, otp);
+    record(AuditEntry::new(AuditKind::DockerRun, &state.session2).with_hash(&state.session_hash2).with_detail("generate-key-pair").with_success(code == 0));
     info!("Command: returns code: {}\n{}\n", code, stdout);
     if code != 0 {
         error!("ERROR: executing 'cosign generate-key-pair'. Code: {}\nError output:\n{}", code, stderr);
     } else {
         println!("Generated key pair");
     }
+    Ok(())
 }
 
-fn get_otp(otp: Option<String>) -> String {
-    if let Some(otp) = otp {
-        otp
-    } else {
-        let prompt = r#"
-        Adding a new authenticate requires an OTP from an existing authenticator.
-            - The new QR code is written to file 'qrcode.svg'
-            - Starting containers can take some while. Hence, wait for a new QR code to appear on your authenticator.
-        Type OTP and press enter: "#;
-
-        print!("{}", prompt);
-
-        // get OTP from user
-        io::stdout().flush().unwrap();
-        let mut otp = String::new();
-        io::stdin().read_line(&mut otp).expect("Error getting OTP");
-        otp.retain(|c| !c.is_whitespace());
-        otp
-    }
-}
-
-fn sign_image(otp: Option<String>, image: String) {
-    let state : State = read_state("state.js"); // default: provide init state
+fn sign_image(otp: Option<String>, image: String) -> Result<()> {
+    let state : State = read_state("state.js")?; // default: provide init state
+    require_attested(&state)?;
 
-    let otp = get_otp(otp);
-    let (code, stdout, stderr) = sh!(r#"docker run --rm -w "/root" -v /var/run/docker.sock:/var/run/docker.sock  -v "$HOME/.docker:/root/.docker" -v "$PWD/cosign_keys:/root/cosign_keys" -e "SCONE_CAS_ADDR=scone-cas.cf" -e "SCONE_CONFIG_ID={}/sign@{}" cosign:scone  /go/bin/cosign {} > qr.output"#, state.session2, otp, image);
+    let otp = get_otp(otp)?;
+    let (code, stdout, stderr) = sh!(r#"docker run --rm -w "/root" -v /var/run/docker.sock:/var/run/docker.sock  -v "$HOME/.docker:/root/.docker" -v "$PWD/cosign_keys:/root/cosign_keys" -e "SCONE_CAS_ADDR={}" -e "SCONE_CONFIG_ID={}/sign@{}" cosign:scone  /go/bin/cosign {} > qr.output"#, state.cas_addr, state.session2, otp, image);
+    record(AuditEntry::new(AuditKind::DockerRun, &state.session2).with_hash(&state.session_hash2).with_detail(&format!("sign {}", image)).with_success(code == 0));
     info!("Command: returns code: {}\n{}\n", code, stdout);
     if code != 0 {
         error!("ERROR: executing 'cosign generate-key-pair'. Code: {}\nError output:\n{}", code, stderr);
     } else {
         println!("Signed image");
     }
+    Ok(())
 }
 
-fn verify_image(otp: Option<String>, image: String) {
-    let state : State = read_state("state.js"); // default: provide init state
+fn verify_image(otp: Option<String>, image: String) -> Result<()> {
+    let state : State = read_state("state.js")?; // default: provide init state
+    require_attested(&state)?;
 
-    let otp = get_otp(otp);
-    let (code, stdout, stderr) = sh!(r#"docker run --rm -w "/root" -v /var/run/docker.sock:/var/run/docker.sock  -v "$HOME/.docker:/root/.docker" -v "$PWD/cosign_keys:/root/cosign_keys" -e "SCONE_CAS_ADDR=scone-cas.cf" -e "SCONE_CONFIG_ID={}/verify@{}" cosign:scone  /go/bin/cosign {} > qr.output"#, state.session2, otp, image);
+    let otp = get_otp(otp)?;
+    let (code, stdout, stderr) = sh!(r#"docker run --rm -w "/root" -v /var/run/docker.sock:/var/run/docker.sock  -v "$HOME/.docker:/root/.docker" -v "$PWD/cosign_keys:/root/cosign_keys" -e "SCONE_CAS_ADDR={}" -e "SCONE_CONFIG_ID={}/verify@{}" cosign:scone  /go/bin/cosign {} > qr.output"#, state.cas_addr, state.session2, otp, image);
+    record(AuditEntry::new(AuditKind::DockerRun, &state.session2).with_hash(&state.session_hash2).with_detail(&format!("verify {}", image)).with_success(code == 0));
     info!("Command: returns code: {}\n{}\n", code, stdout);
     if code != 0 {
         error!("ERROR: executing 'cosign generate-key-pair'. Code: {}\nError output:\n{}", code, stderr);
     } else {
         println!("Verified Key");
     }
+    Ok(())
+}
+
+// fail unless the SCONE CLI uses the CAS that we attested with attest-cas
+fn require_attested(state: &State) -> Result<()> {
+    check_attestation(state.cas_attestation.as_ref(), &state.cas_addr)
 }